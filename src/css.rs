@@ -0,0 +1,419 @@
+use std;
+
+/// The CSS properties recognized when validating styles.
+///
+/// Vendor-prefixed properties and custom properties (`--name`) are always accepted.
+static KNOWN_PROPERTIES: &'static [&'static str] =
+    &["accent-color", "align-content", "align-items", "align-self", "all", "animation",
+      "animation-delay", "animation-direction", "animation-duration", "animation-fill-mode",
+      "animation-iteration-count", "animation-name", "animation-play-state",
+      "animation-timing-function", "appearance", "aspect-ratio", "backdrop-filter",
+      "backface-visibility", "background", "background-attachment", "background-blend-mode",
+      "background-clip", "background-color", "background-image", "background-origin",
+      "background-position", "background-position-x", "background-position-y", "background-repeat",
+      "background-size", "block-size", "border", "border-block", "border-block-color",
+      "border-block-end", "border-block-start", "border-block-style", "border-block-width",
+      "border-bottom", "border-bottom-color", "border-bottom-left-radius",
+      "border-bottom-right-radius", "border-bottom-style", "border-bottom-width", "border-collapse",
+      "border-color", "border-image", "border-image-outset", "border-image-repeat",
+      "border-image-slice", "border-image-source", "border-image-width", "border-inline",
+      "border-inline-color", "border-inline-end", "border-inline-start", "border-inline-style",
+      "border-inline-width", "border-left", "border-left-color", "border-left-style",
+      "border-left-width", "border-radius", "border-right", "border-right-color",
+      "border-right-style", "border-right-width", "border-spacing", "border-style", "border-top",
+      "border-top-color", "border-top-left-radius", "border-top-right-radius", "border-top-style",
+      "border-top-width", "border-width", "bottom", "box-decoration-break", "box-shadow",
+      "box-sizing", "break-after", "break-before", "break-inside", "caption-side", "caret-color",
+      "clear", "clip", "clip-path", "color", "color-scheme", "column-count", "column-fill",
+      "column-gap", "column-rule", "column-rule-color", "column-rule-style", "column-rule-width",
+      "column-span", "column-width", "columns", "contain", "container", "content",
+      "counter-increment", "counter-reset", "counter-set", "cursor", "direction", "display",
+      "empty-cells", "filter", "flex", "flex-basis", "flex-direction", "flex-flow", "flex-grow",
+      "flex-shrink", "flex-wrap", "float", "font", "font-family", "font-feature-settings",
+      "font-kerning", "font-optical-sizing", "font-size", "font-size-adjust", "font-stretch",
+      "font-style", "font-synthesis", "font-variant", "font-variant-caps", "font-variant-ligatures",
+      "font-variant-numeric", "font-variation-settings", "font-weight", "gap", "grid", "grid-area",
+      "grid-auto-columns", "grid-auto-flow", "grid-auto-rows", "grid-column", "grid-column-end",
+      "grid-column-start", "grid-gap", "grid-row", "grid-row-end", "grid-row-start",
+      "grid-template", "grid-template-areas", "grid-template-columns", "grid-template-rows",
+      "height", "hyphens", "image-rendering", "inline-size", "inset", "inset-block",
+      "inset-block-end", "inset-block-start", "inset-inline", "inset-inline-end",
+      "inset-inline-start", "isolation", "justify-content", "justify-items", "justify-self", "left",
+      "letter-spacing", "line-break", "line-height", "list-style", "list-style-image",
+      "list-style-position", "list-style-type", "margin", "margin-block", "margin-block-end",
+      "margin-block-start", "margin-bottom", "margin-inline", "margin-inline-end",
+      "margin-inline-start", "margin-left", "margin-right", "margin-top", "mask", "mask-image",
+      "mask-position", "mask-repeat", "mask-size", "max-block-size", "max-height",
+      "max-inline-size", "max-width", "min-block-size", "min-height", "min-inline-size",
+      "min-width", "mix-blend-mode", "object-fit", "object-position", "opacity", "order", "orphans",
+      "outline", "outline-color", "outline-offset", "outline-style", "outline-width", "overflow",
+      "overflow-wrap", "overflow-x", "overflow-y", "overscroll-behavior", "padding",
+      "padding-block", "padding-block-end", "padding-block-start", "padding-bottom",
+      "padding-inline", "padding-inline-end", "padding-inline-start", "padding-left",
+      "padding-right", "padding-top", "page-break-after", "page-break-before", "page-break-inside",
+      "paint-order", "perspective", "perspective-origin", "place-content", "place-items",
+      "place-self", "pointer-events", "position", "quotes", "resize", "right", "rotate", "row-gap",
+      "scale", "scroll-behavior", "scroll-margin", "scroll-padding", "scroll-snap-align",
+      "scroll-snap-type", "scrollbar-color", "scrollbar-gutter", "scrollbar-width", "shape-outside",
+      "tab-size", "table-layout", "text-align", "text-align-last", "text-decoration",
+      "text-decoration-color", "text-decoration-line", "text-decoration-skip-ink",
+      "text-decoration-style", "text-decoration-thickness", "text-emphasis", "text-emphasis-color",
+      "text-emphasis-style", "text-indent", "text-justify", "text-orientation", "text-overflow",
+      "text-rendering", "text-shadow", "text-transform", "text-underline-offset",
+      "text-underline-position", "text-wrap", "top", "touch-action", "transform", "transform-box",
+      "transform-origin", "transform-style", "transition", "transition-delay",
+      "transition-duration", "transition-property", "transition-timing-function", "translate",
+      "unicode-bidi", "user-select", "vertical-align", "visibility", "white-space", "widows",
+      "width", "will-change", "word-break", "word-spacing", "word-wrap", "writing-mode", "z-index",
+      "zoom"];
+
+/// A single CSS declaration, such as `color: red`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Declaration {
+    pub property: String,
+    pub value: String,
+    pub important: bool,
+}
+
+/// An ordered list of CSS declarations, containing at most one declaration per property.
+#[derive(Clone, Debug, Default)]
+pub struct Declarations {
+    declarations: Vec<Declaration>,
+}
+
+impl Declarations {
+    /// Initializes a new empty list of declarations.
+    pub fn new() -> Declarations {
+        Declarations { declarations: Vec::new() }
+    }
+
+    /// Parses a list of declarations, such as the body of a class or a style option.
+    ///
+    /// Comments are removed, whitespace in values is collapsed and property names are lowercased.
+    /// Declarations which cannot be parsed are skipped and, along with unknown properties, reported
+    /// in `warnings`. Later declarations override earlier ones.
+    /// A declaration without a colon which contains a `{param}` placeholder, and may therefore
+    /// stand for several declarations, is kept verbatim as a property without a value.
+    pub fn parse(text: &str, warnings: &mut Vec<String>) -> Declarations {
+        let mut declarations = Declarations::new();
+        for declaration in split_declarations(&strip_comments(text, warnings), warnings) {
+            if declaration.trim().is_empty() {
+                continue;
+            }
+            let mut parts = declaration.splitn(2, ':');
+            let property = parts.next().unwrap().trim().to_lowercase();
+            let mut value = match parts.next() {
+                Some(value) => value.split_whitespace().collect::<Vec<&str>>().join(" "),
+                None if property.contains('{') => {
+                    declarations.set(Declaration {
+                                         property: declaration.trim().to_owned(),
+                                         value: String::new(),
+                                         important: false,
+                                     });
+                    continue;
+                }
+                None => {
+                    warnings.push(format!("Missing ':' in declaration '{}'", declaration.trim()));
+                    continue;
+                }
+            };
+            if property.is_empty() {
+                warnings.push(format!("Missing property in declaration '{}'", declaration.trim()));
+                continue;
+            }
+            let important = value.to_lowercase().ends_with("!important");
+            if important {
+                let length = value.len() - "!important".len();
+                value = value[..length].trim_end().to_owned();
+            }
+            if value.is_empty() {
+                warnings.push(format!("Missing value for property '{}'", property));
+                continue;
+            }
+            if !is_known_property(&property) {
+                warnings.push(format!("Unknown property '{}'", property));
+            }
+            declarations.set(Declaration {
+                                 property: property,
+                                 value: value,
+                                 important: important,
+                             });
+        }
+        declarations
+    }
+
+    /// Adds a declaration, overriding any previous declaration of the same property.
+    ///
+    /// An `!important` declaration can only be overridden by another `!important` declaration.
+    /// The overriding declaration keeps the position of the one it replaces.
+    pub fn set(&mut self, declaration: Declaration) {
+        match self.declarations
+                  .iter_mut()
+                  .find(|existing| existing.property == declaration.property) {
+            Some(existing) => {
+                if declaration.important || !existing.important {
+                    *existing = declaration;
+                }
+            }
+            None => self.declarations.push(declaration),
+        }
+    }
+
+    /// Merges another list of declarations into this one, with its declarations taking precedence.
+    pub fn merge(&mut self, other: Declarations) {
+        for declaration in other.declarations {
+            self.set(declaration);
+        }
+    }
+
+    /// Formats the declarations as compactly as possible, e.g. `color:red;width:5px`.
+    pub fn to_minified_string(&self) -> String {
+        self.declarations
+            .iter()
+            .map(|declaration| {
+                let value = minify_value(&declaration.value);
+                if value.is_empty() {
+                    declaration.property.clone()
+                } else if declaration.important {
+                    format!("{}:{}!important", declaration.property, value)
+                } else {
+                    format!("{}:{}", declaration.property, value)
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(";")
+    }
+}
+
+impl std::fmt::Display for Declarations {
+    /// Formats the declarations as a normalized style, e.g. `color: red; width: 5px;`.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut separator = "";
+        for declaration in &self.declarations {
+            write!(f, "{}{}", separator, declaration.property)?;
+            if !declaration.value.is_empty() {
+                write!(f, ": {}", declaration.value)?;
+            }
+            if declaration.important {
+                write!(f, " !important")?;
+            }
+            write!(f, ";")?;
+            separator = " ";
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether a property is a known CSS property.
+///
+/// Properties which contain unreplaced template parameters cannot be checked, and are accepted.
+fn is_known_property(property: &str) -> bool {
+    property.starts_with('-') || property.contains('{') ||
+    KNOWN_PROPERTIES.binary_search(&property).is_ok()
+}
+
+/// Removes the spaces following commas in a value, except within quotes or parentheses, whose
+/// content may be significant (e.g. `"Foo, Bar"`).
+fn minify_value(value: &str) -> String {
+    let mut minified = String::new();
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut after_comma = false;
+    for c in value.chars() {
+        if after_comma && c == ' ' {
+            continue;
+        }
+        after_comma = false;
+        match quote {
+            Some(q) => {
+                if c == q {
+                    quote = None;
+                }
+            }
+            None => {
+                match c {
+                    '"' | '\'' => quote = Some(c),
+                    '(' => depth += 1,
+                    ')' if depth > 0 => depth -= 1,
+                    ',' if depth == 0 => after_comma = true,
+                    _ => (),
+                }
+            }
+        }
+        minified.push(c);
+    }
+    minified
+}
+
+/// Removes all `/* ... */` comments from a style.
+fn strip_comments(text: &str, warnings: &mut Vec<String>) -> String {
+    let mut stripped = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("/*") {
+        stripped.push_str(&rest[..start]);
+        match rest[start + 2..].find("*/") {
+            Some(end) => rest = &rest[start + 2 + end + 2..],
+            None => {
+                warnings.push("Unterminated comment".to_owned());
+                rest = "";
+            }
+        }
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+/// Splits a list of declarations on the semicolons which separate them.
+///
+/// Semicolons within quotes or parentheses, such as in `url()` values, do not separate declarations.
+fn split_declarations<'t>(text: &'t str, warnings: &mut Vec<String>) -> Vec<&'t str> {
+    let mut declarations = Vec::new();
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) => {
+                if c == q {
+                    quote = None;
+                }
+            }
+            None => {
+                match c {
+                    '"' | '\'' => quote = Some(c),
+                    '(' => depth += 1,
+                    ')' if depth > 0 => depth -= 1,
+                    ')' => warnings.push("Unbalanced ')'".to_owned()),
+                    ';' if depth == 0 => {
+                        declarations.push(&text[start..i]);
+                        start = i + 1;
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    if quote.is_some() {
+        warnings.push("Unterminated string".to_owned());
+    }
+    if depth > 0 {
+        warnings.push("Unbalanced '('".to_owned());
+    }
+    declarations.push(&text[start..]);
+    declarations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> (Declarations, Vec<String>) {
+        let mut warnings = Vec::new();
+        let declarations = Declarations::parse(text, &mut warnings);
+        (declarations, warnings)
+    }
+
+    #[test]
+    fn known_properties_are_sorted() {
+        let mut sorted = KNOWN_PROPERTIES.to_vec();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted, KNOWN_PROPERTIES);
+    }
+
+    #[test]
+    fn parse_normalizes_declarations() {
+        let (declarations, warnings) = parse(" Color : red ;\n  margin:  0\n  auto ;; ");
+        assert!(warnings.is_empty());
+        assert_eq!(declarations.to_string(), "color: red; margin: 0 auto;");
+    }
+
+    #[test]
+    fn parse_overrides_earlier_declarations() {
+        let (declarations, _) = parse("color: red; width: 5px; color: blue");
+        assert_eq!(declarations.to_string(), "color: blue; width: 5px;");
+    }
+
+    #[test]
+    fn parse_keeps_important_declarations() {
+        let (declarations, _) = parse("color: red !important; color: blue; width: 1px");
+        assert_eq!(declarations.to_string(), "color: red !important; width: 1px;");
+        let (declarations, _) = parse("color: red !important; color: blue!IMPORTANT");
+        assert_eq!(declarations.to_string(), "color: blue !important;");
+    }
+
+    #[test]
+    fn parse_ignores_semicolons_in_strings_and_parentheses() {
+        let (declarations, warnings) =
+            parse("background: url(data:image/png;base64,AAAA); content: \"a;b\"; color: red");
+        assert!(warnings.is_empty());
+        assert_eq!(declarations.to_string(),
+                   "background: url(data:image/png;base64,AAAA); content: \"a;b\"; color: red;");
+    }
+
+    #[test]
+    fn parse_keeps_placeholders_without_a_property() {
+        let (declarations, warnings) = parse("color: red; {style}");
+        assert!(warnings.is_empty());
+        assert_eq!(declarations.to_string(), "color: red; {style};");
+        assert_eq!(declarations.to_minified_string(), "color:red;{style}");
+    }
+
+    #[test]
+    fn parse_strips_comments() {
+        let (declarations, warnings) = parse("color: /* the accent */ red; /* width: 5px; */");
+        assert!(warnings.is_empty());
+        assert_eq!(declarations.to_string(), "color: red;");
+    }
+
+    #[test]
+    fn parse_reports_malformed_declarations() {
+        let (declarations, warnings) = parse("color red; : 5px; width: ; height: 1px /* note");
+        assert_eq!(declarations.to_string(), "height: 1px;");
+        assert_eq!(warnings,
+                   vec!["Unterminated comment",
+                        "Missing ':' in declaration 'color red'",
+                        "Missing property in declaration ': 5px'",
+                        "Missing value for property 'width'"]);
+    }
+
+    #[test]
+    fn parse_reports_unbalanced_delimiters() {
+        let (_, warnings) = parse("background: url(a.png; content: 'a");
+        assert_eq!(warnings, vec!["Unterminated string", "Unbalanced '('"]);
+    }
+
+    #[test]
+    fn parse_checks_property_names() {
+        let (declarations, warnings) = parse("colour: red; inset: 0; accent-color: red; \
+                                              translate: 1px; break-inside: avoid; \
+                                              text-underline-offset: 2px; \
+                                              font-variant-numeric: tabular-nums; \
+                                              -webkit-box-flex: 1; --accent: red; {prop}: 1");
+        assert_eq!(warnings, vec!["Unknown property 'colour'"]);
+        assert_eq!(declarations.declarations.len(), 10);
+    }
+
+    #[test]
+    fn merge_overrides_properties_in_place() {
+        let (mut declarations, _) = parse("color: red; width: 1px");
+        declarations.merge(parse("height: 2px; color: blue").0);
+        assert_eq!(declarations.to_string(), "color: blue; width: 1px; height: 2px;");
+    }
+
+    #[test]
+    fn to_minified_string_removes_whitespace() {
+        let (declarations, _) = parse("font-family: Arial, sans-serif; color: red !important");
+        assert_eq!(declarations.to_minified_string(),
+                   "font-family:Arial,sans-serif;color:red!important");
+        assert_eq!(Declarations::new().to_minified_string(), "");
+    }
+
+    #[test]
+    fn to_minified_string_keeps_strings_and_parentheses() {
+        let (declarations, _) = parse("font-family: \"Foo, Bar\", serif; content: 'a, b'; \
+                                       color: rgb(1, 2, 3)");
+        assert_eq!(declarations.to_minified_string(),
+                   "font-family:\"Foo, Bar\",serif;content:'a, b';color:rgb(1, 2, 3)");
+    }
+}
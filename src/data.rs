@@ -0,0 +1,264 @@
+extern crate elementtree;
+extern crate json;
+
+use std;
+use std::io::Read;
+
+/// Loads a data file, either a JSON file or a CSV file whose first row holds the column names.
+///
+/// A CSV file is loaded as an array with an object for each of its rows.
+pub fn load(path: &std::path::Path) -> Result<json::JsonValue, String> {
+    let mut text = String::new();
+    match std::fs::File::open(path).and_then(|mut file| file.read_to_string(&mut text)) {
+        Err(e) => return Err(format!("'{}': Failed to read data file: {}", path.display(), e)),
+        _ => (),
+    }
+    let extension = path.extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("");
+    match extension.to_lowercase().as_str() {
+        "json" => {
+            match json::parse(&text) {
+                Ok(value) => Ok(value),
+                Err(e) => Err(format!("'{}': Invalid JSON: {}", path.display(), e)),
+            }
+        }
+        "csv" => {
+            match parse_csv(&text) {
+                Ok(value) => Ok(value),
+                Err(e) => Err(format!("'{}': {}", path.display(), e)),
+            }
+        }
+        _ => Err(format!("'{}': Unknown data format, expected .json or .csv", path.display())),
+    }
+}
+
+/// Loads the rows of a data file, each of them an object mapping column names to values.
+pub fn load_rows(path: &std::path::Path) -> Result<Vec<json::JsonValue>, String> {
+    let data = load(path)?;
+    if !data.is_array() || data.members().any(|row| !row.is_object()) {
+        return Err(format!("'{}': Expected an array of objects", path.display()));
+    }
+    Ok(data.members().cloned().collect())
+}
+
+/// Converts a value to text, as it is written in the output.
+pub fn to_text(value: &json::JsonValue) -> String {
+    match *value {
+        json::JsonValue::Null => String::new(),
+        json::JsonValue::Short(_) |
+        json::JsonValue::String(_) => value.as_str().unwrap().to_owned(),
+        _ => value.dump(),
+    }
+}
+
+/// Converts a value to an element which can be bound to a parameter.
+///
+/// Text values become the element's text, with its braces doubled so that data is never mistaken
+/// for parameters. Objects and arrays keep their fields and items within a `<vars>` child, which is
+/// not output, and are marked with a `data-type` attribute.
+pub fn to_element(tag: &str, value: &json::JsonValue) -> elementtree::Element {
+    let mut element = elementtree::Element::new(tag);
+    if value.is_object() {
+        element.set_attr("data-type", "object");
+        let fields = element.append_new_child("vars");
+        for (name, field) in value.entries() {
+            let mut field_element = to_element("var", field);
+            field_element.set_attr("name", name);
+            fields.append_child(field_element);
+        }
+    } else if value.is_array() {
+        element.set_attr("data-type", "array");
+        let items = element.append_new_child("vars");
+        for member in value.members() {
+            items.append_child(to_element("item", member));
+        }
+    } else {
+        element.set_text(escape_braces(&to_text(value)));
+    }
+    element
+}
+
+/// Converts a value to the values bound to a parameter: a list of items for an array, and a single
+/// value otherwise.
+pub fn to_values(value: &json::JsonValue) -> Vec<elementtree::Element> {
+    values_of(&to_element("param", value))
+}
+
+/// Converts a value to a parameter passed to an include.
+///
+/// An array is passed as a list, with an item for each of its values.
+pub fn to_param(name: &str, value: &json::JsonValue) -> elementtree::Element {
+    let mut param = elementtree::Element::new("param");
+    if value.is_array() {
+        for member in value.members() {
+            param.append_child(to_element("item", member));
+        }
+    } else {
+        param = to_element("param", value);
+    }
+    param.set_attr("name", name);
+    param
+}
+
+/// Finds the values of a field of an object created by `to_element`.
+pub fn field(element: &elementtree::Element, name: &str) -> Option<Vec<elementtree::Element>> {
+    if element.get_attr("data-type") != Some("object") {
+        return None;
+    }
+    element
+        .find("vars")?
+        .find_all("var")
+        .find(|field| field.get_attr("name") == Some(name))
+        .map(values_of)
+}
+
+/// Finds the values held by an element created by `to_element`: its items if it is an array, and
+/// the element itself otherwise.
+fn values_of(element: &elementtree::Element) -> Vec<elementtree::Element> {
+    match element.get_attr("data-type") {
+        Some("array") => {
+            element
+                .find("vars")
+                .map(|items| items.children().cloned().collect())
+                .unwrap_or_default()
+        }
+        _ => vec![element.clone()],
+    }
+}
+
+/// Doubles the braces in a text.
+fn escape_braces(text: &str) -> String {
+    text.replace("{", "{{").replace("}", "}}")
+}
+
+/// Parses CSV text into an array of objects, using the first row as column names.
+///
+/// Fields may be quoted with `"`, in which case they may contain commas, line breaks and doubled
+/// quotes. Empty lines are skipped, as is the byte order mark which some editors write first.
+fn parse_csv(text: &str) -> Result<json::JsonValue, String> {
+    let text = text.trim_start_matches('\u{feff}');
+    let mut records = parse_csv_records(text)?.into_iter();
+    let columns: Vec<String> = match records.next() {
+        Some(columns) => columns.iter().map(|column| column.trim().to_owned()).collect(),
+        None => return Err("Missing header row".to_owned()),
+    };
+    let mut rows = json::JsonValue::new_array();
+    for (i, record) in records.enumerate() {
+        if record.len() != columns.len() {
+            return Err(format!("Row {} has {} fields, expected {}",
+                               i + 1,
+                               record.len(),
+                               columns.len()));
+        }
+        let mut row = json::JsonValue::new_object();
+        for (column, field) in columns.iter().zip(record) {
+            row[column.as_str()] = field.into();
+        }
+        rows.push(row).unwrap();
+    }
+    Ok(rows)
+}
+
+/// Splits CSV text into records, and each record into its fields.
+fn parse_csv_records(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' => quoted = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' => (),
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                if record.len() > 1 || !record[0].is_empty() {
+                    records.push(std::mem::take(&mut record));
+                } else {
+                    record.clear();
+                }
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quoted field".to_owned());
+    }
+    record.push(field);
+    if record.len() > 1 || !record[0].is_empty() {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(text: &str) -> Vec<Vec<String>> {
+        parse_csv_records(text).unwrap()
+    }
+
+    #[test]
+    fn parse_csv_records_splits_fields() {
+        assert_eq!(records("a,b,c\n1,,3"), vec![vec!["a", "b", "c"], vec!["1", "", "3"]]);
+    }
+
+    #[test]
+    fn parse_csv_records_handles_quoted_fields() {
+        assert_eq!(records("name,quote\n\"Doe, Jane\",\"She said \"\"hi\"\"\""),
+                   vec![vec!["name", "quote"], vec!["Doe, Jane", "She said \"hi\""]]);
+        assert_eq!(records("a\n\"two\nlines\""), vec![vec!["a"], vec!["two\nlines"]]);
+    }
+
+    #[test]
+    fn parse_csv_records_handles_line_endings() {
+        assert_eq!(records("a,b\r\n1,2\r\n\r\n3,4\r\n"),
+                   vec![vec!["a", "b"], vec!["1", "2"], vec!["3", "4"]]);
+    }
+
+    #[test]
+    fn parse_csv_records_rejects_unterminated_quotes() {
+        assert_eq!(parse_csv_records("a\n\"open"),
+                   Err("Unterminated quoted field".to_owned()));
+    }
+
+    #[test]
+    fn parse_csv_uses_header_as_column_names() {
+        let rows = parse_csv(" name , str\nJoe,12\n\"Doe, Jane\",8\n").unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["name"], "Joe");
+        assert_eq!(rows[0]["str"], "12");
+        assert_eq!(rows[1]["name"], "Doe, Jane");
+    }
+
+    #[test]
+    fn parse_csv_skips_byte_order_mark() {
+        let rows = parse_csv("\u{feff}name,str\r\nJoe,12\r\n").unwrap();
+        assert_eq!(rows[0]["name"], "Joe");
+    }
+
+    #[test]
+    fn parse_csv_checks_field_count() {
+        assert_eq!(parse_csv("a,b\n1,2\n3\n"), Err("Row 2 has 1 fields, expected 2".to_owned()));
+        assert_eq!(parse_csv("a,b\n1,2,3\n"), Err("Row 1 has 3 fields, expected 2".to_owned()));
+    }
+
+    #[test]
+    fn parse_csv_requires_header() {
+        assert_eq!(parse_csv("\n\n"), Err("Missing header row".to_owned()));
+    }
+}
@@ -0,0 +1,131 @@
+/// The default dialect, used when a target does not specify one.
+pub static DEFAULT_DIALECT: &'static str = "generic";
+
+/// A strategy for keeping BBCode metacharacters in text from being interpreted by the forum.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Escape {
+    /// Text is written as it is.
+    None,
+    /// A zero-width space is inserted after every `[`, which breaks tags without being visible.
+    ZeroWidthSpace,
+    /// Every `[` is written as an HTML entity, for forums which decode them in posts.
+    Entity,
+    /// Text containing `[` is wrapped in the specified tag, which disables parsing of its content.
+    /// A zero-width space is inserted in any closing tag of the same name within the text.
+    Tag(&'static str),
+}
+
+impl Escape {
+    /// Finds an escaping strategy by name.
+    pub fn from_name(name: &str) -> Option<Escape> {
+        match name {
+            "none" => Some(Escape::None),
+            "zwsp" => Some(Escape::ZeroWidthSpace),
+            "entity" => Some(Escape::Entity),
+            "noparse" => Some(Escape::Tag("noparse")),
+            "plain" => Some(Escape::Tag("plain")),
+            _ => None,
+        }
+    }
+
+    /// Escapes the metacharacters of a text string.
+    pub fn apply(&self, text: &str) -> String {
+        if !text.contains('[') {
+            return text.to_owned();
+        }
+        match *self {
+            Escape::None => text.to_owned(),
+            Escape::ZeroWidthSpace => text.replace("[", "[\u{200B}"),
+            Escape::Entity => text.replace("[", "&#91;"),
+            Escape::Tag(tag) => {
+                format!("[{}]{}[/{}]",
+                        tag.to_uppercase(),
+                        Escape::break_closing_tags(text, tag),
+                        tag.to_uppercase())
+            }
+        }
+    }
+
+    /// Inserts a zero-width space in every closing tag of the specified name, in any case, so that
+    /// it cannot end the tag which wraps the text.
+    fn break_closing_tags(text: &str, tag: &str) -> String {
+        // ASCII lowercasing keeps the positions of the matches the same in the original text
+        let closing = format!("[/{}", tag);
+        let mut broken = String::new();
+        let mut last_end = 0;
+        for (i, _) in text.to_ascii_lowercase().match_indices(&closing) {
+            broken.push_str(&text[last_end..i + 1]);
+            broken.push('\u{200B}');
+            last_end = i + 1;
+        }
+        broken.push_str(&text[last_end..]);
+        broken
+    }
+}
+
+/// Merges the wrappers of adjacent escaped texts, so that a wrapper which is closed only to be
+/// opened again right away is written once.
+///
+/// Closing tags within wrapped text are broken when it is escaped, so only the wrappers themselves
+/// can be merged.
+pub fn merge_wrappers(output: &str) -> String {
+    let mut merged = output.to_owned();
+    for tag in &["NOPARSE", "PLAIN"] {
+        merged = merged.replace(&format!("[/{}][{}]", tag, tag), "");
+    }
+    merged
+}
+
+/// The specifics of the BBCode flavour used by a forum.
+#[derive(Clone, Debug)]
+pub struct Dialect {
+    pub escape: Escape,
+    /// The maximum number of characters in a post, if the forum limits it.
+    pub max_length: Option<usize>,
+}
+
+impl Dialect {
+    /// Finds a known dialect by name.
+    pub fn from_name(name: &str) -> Option<Dialect> {
+        let (escape, max_length) = match name {
+            "generic" => (Escape::ZeroWidthSpace, None),
+            "phpbb" => (Escape::ZeroWidthSpace, Some(60000)),
+            "vbulletin" => (Escape::Tag("noparse"), Some(10000)),
+            "xenforo" => (Escape::Tag("plain"), Some(25000)),
+            "rpnation" => (Escape::Entity, Some(25000)),
+            _ => return None,
+        };
+        Some(Dialect {
+                 escape: escape,
+                 max_length: max_length,
+             })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_without_brackets_is_not_escaped() {
+        assert_eq!(Escape::Tag("noparse").apply("a]b"), "a]b");
+        assert_eq!(Escape::Entity.apply("a]b"), "a]b");
+    }
+
+    #[test]
+    fn closing_tags_are_broken_in_any_case() {
+        assert_eq!(Escape::Tag("plain").apply("[b][/PLAIN][/plain]"),
+                   "[PLAIN][b][\u{200B}/PLAIN][\u{200B}/plain][/PLAIN]");
+        assert_eq!(Escape::Tag("plain").apply("[/noparse]"), "[PLAIN][/noparse][/PLAIN]");
+    }
+
+    #[test]
+    fn only_adjacent_wrappers_are_merged() {
+        assert_eq!(merge_wrappers("[NOPARSE][a][/NOPARSE][NOPARSE][b][/NOPARSE]"),
+                   "[NOPARSE][a][b][/NOPARSE]");
+        assert_eq!(merge_wrappers("[NOPARSE][a][/NOPARSE] [NOPARSE][b][/NOPARSE]"),
+                   "[NOPARSE][a][/NOPARSE] [NOPARSE][b][/NOPARSE]");
+        assert_eq!(merge_wrappers("[PLAIN][a][/PLAIN][NOPARSE][b][/NOPARSE]"),
+                   "[PLAIN][a][/PLAIN][NOPARSE][b][/NOPARSE]");
+    }
+}
@@ -1,7 +1,19 @@
+// The codebase favours explicit `match` expressions and spelled-out types over the terser forms
+// suggested by these lints.
+#![allow(unknown_lints)]
+#![allow(clippy::single_match, clippy::match_like_matches_macro, clippy::match_single_binding,
+         clippy::manual_unwrap_or, clippy::manual_unwrap_or_default, clippy::redundant_static_lifetimes,
+         clippy::redundant_field_names, clippy::unused_unit)]
+
 extern crate clap;
 
+mod css;
+mod data;
+mod dialect;
 mod parser;
+mod posts;
 mod project;
+mod whitespace;
 
 /// The default path to a project's definition file.
 static DEFAULT_PROJECT_PATH: &'static str = "project.xml";
@@ -11,6 +23,8 @@ static DEFAULT_PROJECT_PATH: &'static str = "project.xml";
 /// The path to the project file can be specified using the `-p` argument.
 /// The build target can be optionally specified as the only position argument; if left unspecified,
 /// the project's default target will be built.
+/// Variables can be defined with any number of `-D name=value` arguments, overriding those defined
+/// in the project and its files.
 fn main() {
     // Initialize the argument parser
     let matches = clap::App::new("bbcoder")
@@ -22,9 +36,34 @@ fn main() {
                  .long("path")
                  .default_value(DEFAULT_PROJECT_PATH)
                  .help("Path to the BBCoder project file"))
+        .arg(clap::Arg::with_name("define")
+                 .short("D")
+                 .long("define")
+                 .value_name("NAME=VALUE")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .help("Defines a variable, overriding any existing definition"))
+        .arg(clap::Arg::with_name("minify")
+                 .long("minify")
+                 .help("Minifies the styles of all targets"))
+        .arg(clap::Arg::with_name("line-endings")
+                 .long("line-endings")
+                 .takes_value(true)
+                 .possible_values(&["lf", "crlf"])
+                 .help("Sets the line endings of all targets"))
+        .arg(clap::Arg::with_name("trailing-newline")
+                 .long("trailing-newline")
+                 .takes_value(true)
+                 .possible_values(&["keep", "always", "never"])
+                 .help("Sets whether all targets end with a newline"))
+        .arg(clap::Arg::with_name("strict")
+                 .long("strict")
+                 .help("Treats references to unknown classes as errors"))
         .arg(clap::Arg::with_name("TARGET")
                  .index(1)
-                 .help("The target to build (default: all)"))
+                 .multiple(true)
+                 .help("The targets, groups or target patterns to build (default: all)"))
         .get_matches();
 
     // Load the project
@@ -37,38 +76,98 @@ fn main() {
         }
         _ => (),
     };
-    if !proj.targets.contains_key(&proj.default_target) &&
-       proj.default_target != project::ALL_TARGETS {
+    for define in matches.values_of("define").into_iter().flatten() {
+        match project::parse_define(define) {
+            Ok((name, value)) => {
+                proj.defines.insert(name, value);
+            }
+            Err(e) => {
+                println!("ERROR: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    proj.strict = matches.is_present("strict");
+    if matches.is_present("minify") {
+        for target in proj.targets.values_mut() {
+            target.minify = true;
+        }
+    }
+    match matches.value_of("line-endings") {
+        Some(name) => {
+            for target in proj.targets.values_mut() {
+                target.line_endings = whitespace::LineEndings::from_name(name).unwrap();
+            }
+        }
+        None => (),
+    }
+    match matches.value_of("trailing-newline") {
+        Some(name) => {
+            for target in proj.targets.values_mut() {
+                target.trailing_newline = whitespace::TrailingNewline::from_name(name).unwrap();
+            }
+        }
+        None => (),
+    }
+    if proj.find_targets(&proj.default_target).is_err() {
         println!("WARNING: Default target '{}' not found",
                  proj.default_target);
     }
 
-    // Build all the desired targets
-    let mut target_name = proj.default_target.clone();
-    if matches.is_present("TARGET") {
-        target_name = matches.value_of("TARGET").unwrap().to_owned();
-    }
-    if target_name == project::ALL_TARGETS {
-        for target in proj.targets.keys() {
-            build_target(&proj, target);
+    // Find all the desired targets, skipping those designated more than once
+    let target_names: Vec<String> = match matches.values_of("TARGET") {
+        Some(names) => names.map(|name| name.to_owned()).collect(),
+        None => vec![proj.default_target.clone()],
+    };
+    let mut builds: Vec<(String, Option<String>)> = Vec::new();
+    for target_name in target_names {
+        match proj.find_targets(&target_name) {
+            Ok(targets) => {
+                for build in targets {
+                    if !builds.contains(&build) {
+                        builds.push(build);
+                    }
+                }
+            }
+            Err(e) => {
+                println!("ERROR: {}", e);
+                std::process::exit(1);
+            }
         }
-    } else if !proj.targets.contains_key(&target_name) {
-        println!("ERROR: Target '{}' not found", &target_name);
-        std::process::exit(1);
-    } else {
-        build_target(&proj, &target_name);
+    }
+
+    // Build all the desired targets
+    for (target, body_name) in builds {
+        build_target(&proj, &target, body_name.as_deref());
     }
     std::process::exit(0);
 }
 
 /// Parses a project's target and outputs the BBCoded to a file
-fn build_target(project: &project::Project, target: &String) {
-    let filename = &project.targets[target];
-    let output_path = std::path::Path::new("target").join(format!("{}.txt", target));
-    let mut parser = parser::Parser::new(&project);
+///
+/// All the bodies of the target's file are built, unless the target's source or the body name given
+/// designates a single one.
+fn build_target(project: &project::Project, target: &String, body_name: Option<&str>) {
+    let filename = &project.targets[target].src;
+    let source_body_name = project.targets[target].body.as_deref();
+    let output_path = match (source_body_name, body_name) {
+        (Some(source_body_name), Some(body_name)) if source_body_name != body_name => {
+            println!("ERROR: Target '{}' has no body '{}'", target, body_name);
+            std::process::exit(1);
+        }
+        (None, Some(body_name)) => {
+            std::path::Path::new("target").join(format!("{}.{}.txt", target, body_name))
+        }
+        _ => std::path::Path::new("target").join(format!("{}.txt", target)),
+    };
+    let body_name = source_body_name.or(body_name);
+    let mut parser = parser::Parser::new(project);
     match project.find_file(filename, std::path::Path::new(filename).parent().unwrap()) {
         Some(root_path) => {
-            match parser.output_bbcode(&root_path, &output_path) {
+            match parser.output_bbcode(&project.targets[target],
+                                       &root_path,
+                                       body_name,
+                                       &output_path) {
                 Err(e) => {
                     println!("ERROR: {}", e);
                     std::process::exit(1);
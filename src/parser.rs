@@ -1,59 +1,459 @@
 extern crate elementtree;
+extern crate json;
 extern crate regex;
 
+use css;
+use data;
+use dialect;
+use posts;
 use project;
 use std;
 use std::io::Write;
+use whitespace;
+
+/// The tags whose option is always a style, even if they have no classes; it is then checked, and
+/// only rewritten when minifying.
+static STYLE_TAGS: &'static [&'static str] = &["div"];
+
+/// The names of BBXML elements, which `<tag>` elements cannot output.
+static RESERVED_TAGS: &'static [&'static str] = &["block", "br", "d", "for", "in", "include", "let",
+                                                  "li", "tag", "vars"];
+
+/// The values bound to each parameter name in the current scope.
+///
+/// A parameter may hold several values, as is the case for list parameters iterated by `<for>`.
+type Replacements = std::collections::HashMap<String, Vec<elementtree::Element>>;
+
+/// A class as it is defined in a BBXML file, before its inheritance is resolved.
+struct ClassDefinition {
+    body: String,
+    extends: Vec<String>,
+    filename: String,
+    namespace: String,
+}
+
+/// A reference to a class from an element, along with the arguments passed to it.
+struct ClassReference {
+    name: String,
+    args: Vec<(String, String)>,
+}
+
+/// A template, along with the file and namespace it was defined in.
+#[derive(Clone)]
+struct Template {
+    element: elementtree::Element,
+    filename: String,
+    namespace: String,
+}
 
 /// A BBXML parser which can be used to convert it to BBCode.
 pub struct Parser<'a> {
+    class_definitions: std::collections::HashMap<String, ClassDefinition>,
     classes: std::collections::HashMap<String, String>,
+    data: Replacements,
+    escape: dialect::Escape,
+    globals: Replacements,
+    minify: bool,
     project: &'a project::Project,
-    templates: std::collections::HashMap<String, elementtree::Element>,
+    location: String,
+    element_path: Vec<String>,
+    namespace: String,
+    pending_space: bool,
+    processed_files: std::collections::HashSet<(std::path::PathBuf, String)>,
+    shadowed: std::collections::HashSet<String>,
+    skip_space: bool,
+    templates: std::collections::HashMap<String, Template>,
+    vars: std::collections::HashMap<String, elementtree::Element>,
+    warnings: std::collections::HashSet<String>,
+    whitespace: whitespace::Whitespace,
 }
 
 impl<'a> Parser<'a> {
     /// Initializes a new empty parser.
     pub fn new(project: &'a project::Project) -> Parser<'a> {
         Parser {
+            class_definitions: std::collections::HashMap::new(),
             classes: std::collections::HashMap::new(),
+            data: Replacements::new(),
+            escape: dialect::Escape::None,
+            globals: Replacements::new(),
+            minify: false,
             project: project,
+            location: String::new(),
+            element_path: Vec::new(),
+            namespace: String::new(),
+            pending_space: false,
+            processed_files: std::collections::HashSet::new(),
+            shadowed: std::collections::HashSet::new(),
+            skip_space: true,
             templates: std::collections::HashMap::new(),
+            vars: std::collections::HashMap::new(),
+            warnings: std::collections::HashSet::new(),
+            whitespace: whitespace::Whitespace::Compact,
         }
     }
 
-    /// Parses a BBXML file and outputs its body as BBCode.
+    /// Parses a BBXML file and outputs its bodies as BBCode.
+    ///
+    /// A file may contain several bodies, each of them written to its own output: an unnamed body
+    /// is written to `output_path`, and a body named `name` next to it with `.name` inserted
+    /// before the extension. If a body name is given, only that body is written, to `output_path`.
+    ///
+    /// Variables are visible throughout the body; those defined by the project are overridden by
+    /// the target's, which are in turn overridden by those defined in the BBXML files and finally
+    /// by those defined on the command line. A template's own variables override all of these
+    /// except those defined on the command line, and are overridden by the parameters passed to its
+    /// include.
     pub fn output_bbcode(&mut self,
+                         target: &project::Target,
                          root_path: &std::path::Path,
+                         body_name: Option<&str>,
                          output_path: &std::path::Path)
                          -> Result<(), String> {
         // Process the root file for its includes, templates and paths
-        self.process_file(root_path)?;
+        self.process_file(root_path, "")?;
+        self.resolve_templates()?;
+        self.escape = target.dialect.escape;
+        self.minify = target.minify;
 
-        // Convert this file's body to BBCode
+        // Convert this file's bodies to BBCode
         // TODO(Lyrositor) Re-parsing this file is a bit of a waste, since we have already done it
         let root: elementtree::Element = elementtree::Element::from_reader(
             std::io::BufReader::new(std::fs::File::open(root_path).unwrap())).unwrap();
-        match root.find("body") {
-            Some(body) => {
-                match std::fs::create_dir_all(output_path.parent().unwrap()) {
-                    Err(e) => return Err(format!("Failed to create directory: {}", e)),
-                    _ => (),
+        match std::fs::create_dir_all(output_path.parent().unwrap()) {
+            Err(e) => return Err(format!("Failed to create directory: {}", e)),
+            _ => (),
+        }
+        match target.template {
+            Some(ref template) => {
+                return self.output_rows(target, template, &root, root_path, body_name, output_path)
+            }
+            None => (),
+        }
+
+        // The outputs of named bodies sit next to the numbered posts an output is split into, so
+        // their names cannot be numbers
+        for name in root.find_all("body").filter_map(|body| body.get_attr("name")) {
+            if name.is_empty() || name.chars().all(|c| c.is_ascii_digit()) {
+                return Err(format!("'{}': Invalid body name '{}'", root_path.display(), name));
+            }
+        }
+        match body_name {
+            Some(body_name) => {
+                match root.find_all("body").find(|body| body.get_attr("name") == Some(body_name)) {
+                    Some(body) => self.output_body(target, &root, body, root_path, output_path)?,
+                    None => {
+                        let known_names: Vec<String> = root.find_all("body")
+                            .filter_map(|body| body.get_attr("name"))
+                            .map(|name| name.to_owned())
+                            .collect();
+                        return Err(format!("Body '{}' not found in '{}'{}",
+                                           body_name,
+                                           root_path.display(),
+                                           Parser::suggest(body_name, known_names.iter())));
+                    }
+                }
+            }
+            None => {
+                let mut unnamed = false;
+                for body in root.find_all("body") {
+                    let body_path = match body.get_attr("name") {
+                        Some(name) => output_path.with_extension(format!("{}.txt", name)),
+                        None if unnamed => {
+                            return Err(format!("'{}': Only one body may be unnamed",
+                                               root_path.display()))
+                        }
+                        None => {
+                            unnamed = true;
+                            output_path.to_owned()
+                        }
+                    };
+                    self.output_body(target, &root, body, root_path, &body_path)?;
+                }
+                if root.find("body").is_none() {
+                    return Err("No body was found in target root".to_owned());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Outputs an include of a template for each row of the target's data file.
+    ///
+    /// The columns of each row are passed as parameters to the include, whose output is written
+    /// next to `output_path` with the row's name inserted before the extension: the value of its
+    /// key column, or else its number. If a row name is given, only that row is written, to
+    /// `output_path`.
+    fn output_rows(&mut self,
+                   target: &project::Target,
+                   template: &str,
+                   root: &elementtree::Element,
+                   root_path: &std::path::Path,
+                   row_name: Option<&str>,
+                   output_path: &std::path::Path)
+                   -> Result<(), String> {
+        let data_path = match target.data {
+            Some(ref data) => {
+                match self.project.find_file(data, root_path.parent().unwrap()) {
+                    Some(data_path) => data_path,
+                    None => return Err(format!("Data file '{}' not found", data)),
+                }
+            }
+            None => return Err("Missing 'data' attribute in template target".to_owned()),
+        };
+        // Every row is named before any is output, since rows with the same name would overwrite
+        // each other's output
+        let rows = data::load_rows(&data_path)?;
+        let mut names: Vec<String> = Vec::new();
+        let mut numbers: std::collections::HashMap<String, usize> =
+            std::collections::HashMap::new();
+        for (i, row) in rows.iter().enumerate() {
+            let name = match target.key {
+                Some(ref key) => {
+                    match row[key.as_str()] {
+                        json::JsonValue::Null => {
+                            return Err(format!("'{}': Row {} has no '{}' column",
+                                               data_path.display(),
+                                               i + 1,
+                                               key))
+                        }
+                        ref value => {
+                            // Key values are used in file names, where they cannot be left empty
+                            let name = Parser::file_name(&data::to_text(value));
+                            if name.is_empty() {
+                                return Err(format!("'{}': Row {} has an empty '{}' column",
+                                                   data_path.display(),
+                                                   i + 1,
+                                                   key));
+                            }
+                            name
+                        }
+                    }
+                }
+                None => (i + 1).to_string(),
+            };
+            match numbers.insert(name.clone(), i + 1) {
+                Some(previous) => {
+                    return Err(format!("'{}': Rows {} and {} are both named '{}'",
+                                       data_path.display(),
+                                       previous,
+                                       i + 1,
+                                       name))
+                }
+                None => (),
+            }
+            names.push(name);
+        }
+
+        let mut found = false;
+        for (row, name) in rows.iter().zip(names) {
+            let row_path = match row_name {
+                Some(row_name) if row_name == name => output_path.to_owned(),
+                Some(_) => continue,
+                None => output_path.with_extension(format!("{}.txt", name)),
+            };
+            found = true;
+
+            // The row is output by a body which only includes the template
+            let mut body = elementtree::Element::new("body");
+            body.set_attr("name", name.as_str());
+            {
+                let include = body.append_new_child("include");
+                include.set_attr("template", template);
+                // Empty arrays are left unbound, since a list parameter needs at least one item
+                for (column, value) in row.entries() {
+                    if !value.is_array() || !value.is_empty() {
+                        include.append_child(data::to_param(column, value));
+                    }
                 }
-                let mut output = std::io::BufWriter::new(std::fs::File::create(output_path)
-                                                             .unwrap());
-                self.parse_element(body, &mut output, &std::collections::HashMap::new())?;
+            }
+            self.output_body(target, root, &body, root_path, &row_path)?;
+        }
+        match row_name {
+            Some(row_name) if !found => {
+                Err(format!("Row '{}' not found in '{}'", row_name, data_path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Outputs a single body of a BBXML file as BBCode.
+    fn output_body(&mut self,
+                   target: &project::Target,
+                   root: &elementtree::Element,
+                   body: &elementtree::Element,
+                   root_path: &std::path::Path,
+                   output_path: &std::path::Path)
+                   -> Result<(), String> {
+        self.whitespace = target.whitespace;
+        self.skip_space = true;
+        self.pending_space = false;
+        self.location = match body.get_attr("name") {
+            Some(name) => format!("'{}', body '{}'", root_path.display(), name),
+            None => format!("'{}', body", root_path.display()),
+        };
+
+        // The body uses the file's whitespace mode unless it sets its own
+        let mut body = body.clone();
+        match root.get_attr("whitespace") {
+            Some(whitespace) if body.get_attr("whitespace").is_none() => {
+                body.set_attr("whitespace", whitespace);
+            }
+            _ => (),
+        }
+
+        // The body may extend a template, which it is then parsed as, in the template's namespace;
+        // the body's variables are then those of the template
+        let (body, namespace) = match body.get_attr("extends") {
+            Some(base_name) => {
+                let base_name = &Parser::qualify(&self.templates, "", base_name);
+                let base = match self.templates.get(base_name) {
+                    Some(base) => base.clone(),
+                    None => {
+                        return Err(format!("{}: Body extends unknown template '{}'{}",
+                                           self.location,
+                                           base_name,
+                                           Parser::suggest(base_name, self.templates.keys())))
+                    }
+                };
+                let source = self.location.clone();
+                (self.extend_template(&base.element, &body, "", &source), base.namespace)
+            }
+            None => (body, String::new()),
+        };
+        let body_vars = match body.find("vars") {
+            Some(vars) => project::parse_vars(vars)?,
+            None => std::collections::HashMap::new(),
+        };
+
+        let mut replacements = Replacements::new();
+        for vars in &[&self.project.vars, &target.vars, &self.vars] {
+            for (name, value) in vars.iter() {
+                replacements.insert(name.clone(), vec![value.clone()]);
+            }
+        }
+        self.bind_namespace_vars(&namespace, &mut replacements);
+        replacements.extend(self.data.clone());
+        for vars in &[&body_vars, &self.project.defines] {
+            for (name, value) in vars.iter() {
+                replacements.insert(name.clone(), vec![value.clone()]);
+            }
+        }
+        let mut output: Vec<u8> = Vec::new();
+        self.namespace = namespace;
+        self.globals = replacements.clone();
+        self.parse_element(&body, &mut output, &replacements)?;
+
+        // Text escaped in several pieces is wrapped only once where the pieces meet
+        // Newlines may come from plain text in files with different line endings, and are
+        // normalized before the output is measured and written
+        let output = match String::from_utf8(output) {
+            Ok(output) => output,
+            Err(e) => return Err(format!("Invalid output: {}", e)),
+        };
+        let output = whitespace::format_lines(&dialect::merge_wrappers(&output),
+                                              target.line_endings,
+                                              target.trailing_newline);
+
+        // Outputs over the forum's maximum length are split into several posts if allowed
+        let length = posts::length(&output);
+        let max_length = match target.dialect.max_length {
+            Some(max_length) if length > max_length => max_length,
+            _ => return self.write_output(&output, target, output_path),
+        };
+        if !target.split {
+            self.warn(format!("'{}' is {} characters long, over the maximum of {}",
+                              output_path.display(),
+                              length,
+                              max_length));
+            return self.write_output(&output, target, output_path);
+        }
 
+        // Room is left for the newline which may be added at the end of each post
+        let reserved = match (target.trailing_newline, target.line_endings) {
+            (whitespace::TrailingNewline::Always, whitespace::LineEndings::Lf) => 1,
+            (whitespace::TrailingNewline::Always, whitespace::LineEndings::CrLf) => 2,
+            _ => 0,
+        };
+        let posts = posts::split(&output, max_length.saturating_sub(reserved));
+        for (i, post) in posts.iter().enumerate() {
+            let post = whitespace::format_lines(post, target.line_endings, target.trailing_newline);
+            let post_path = output_path.with_extension(format!("{}.txt", i + 1));
+            Parser::write_post(&post, target, &post_path)?;
+        }
+        Parser::remove_stale_posts(output_path, posts.len())
+    }
+
+    /// Writes an output as a single post, removing the posts it was split into by a previous build,
+    /// if any.
+    fn write_output(&self,
+                    output: &str,
+                    target: &project::Target,
+                    output_path: &std::path::Path)
+                    -> Result<(), String> {
+        Parser::write_post(output, target, output_path)?;
+        Parser::remove_stale_posts(output_path, 0)
+    }
+
+    /// Writes a post to a file, and reports its length.
+    fn write_post(post: &str,
+                  target: &project::Target,
+                  path: &std::path::Path)
+                  -> Result<(), String> {
+        match std::fs::File::create(path).and_then(|mut file| file.write_all(post.as_bytes())) {
+            Err(e) => return Err(format!("Failed to write to output: {}", e)),
+            _ => (),
+        }
+        let length = posts::length(post);
+        match target.dialect.max_length {
+            Some(max_length) => {
+                println!("{}: {} / {} characters", path.display(), length, max_length)
             }
-            None => return Err("No body was found in target root".to_owned()),
+            None => println!("{}: {} characters", path.display(), length),
         }
+        Ok(())
+    }
 
+    /// Removes the outputs left by a previous build which no longer belong to an output split into
+    /// `count` posts: the posts numbered after `count`, and the unsplit output if it was split.
+    fn remove_stale_posts(output_path: &std::path::Path, count: usize) -> Result<(), String> {
+        let mut stale_paths = Vec::new();
+        if count > 0 {
+            stale_paths.push(output_path.to_owned());
+        }
+        let mut i = count + 1;
+        loop {
+            let post_path = output_path.with_extension(format!("{}.txt", i));
+            if !post_path.exists() {
+                break;
+            }
+            stale_paths.push(post_path);
+            i += 1;
+        }
+        for path in stale_paths {
+            match std::fs::remove_file(&path) {
+                Err(ref e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(format!("Failed to remove '{}': {}", path.display(), e))
+                }
+                _ => (),
+            }
+        }
         Ok(())
     }
 
     /// Processes a single file for its classes and templates.
-    fn process_file(&mut self, file_path: &std::path::Path) -> Result<(), String> {
+    ///
+    /// The names of the classes and templates are prefixed with the file's namespace, which is
+    /// either empty or ends with `:`.
+    /// Files which were already processed in this namespace, directly or through another include,
+    /// are skipped.
+    fn process_file(&mut self, file_path: &std::path::Path, namespace: &str) -> Result<(), String> {
         let filename = file_path.to_str().unwrap();
+        let canonical_path = std::fs::canonicalize(file_path).unwrap_or(file_path.to_owned());
+        if !self.processed_files.insert((canonical_path, namespace.to_owned())) {
+            return Ok(());
+        }
 
         // Open the XML file
         let file: std::fs::File = match std::fs::File::open(file_path) {
@@ -70,15 +470,39 @@ impl<'a> Parser<'a> {
         }
 
         // Process this file's includes
-        match self.process_includes(&root, &file_path.parent().unwrap()) {
+        match self.process_includes(&root, file_path.parent().unwrap(), namespace) {
             Err(e) => return Err(format!("'{}': {}", filename, e)),
             _ => (),
         }
 
+        // Process this file's variables, which are placed in its namespace like its definitions
+        match root.find("vars") {
+            Some(vars) => {
+                match project::parse_vars(vars) {
+                    Ok(vars) => {
+                        for (name, value) in vars {
+                            self.vars.insert(format!("{}{}", namespace, name),
+                                             Parser::in_namespace(&value, namespace));
+                        }
+                    }
+                    Err(e) => return Err(format!("'{}': {}", filename, e)),
+                }
+            }
+            None => (),  // No variables defined in this file
+        }
+
+        // Load this file's data files, each of them bound to a parameter
+        for data_element in root.find_all("data") {
+            match self.process_data(data_element, file_path.parent().unwrap()) {
+                Err(e) => return Err(format!("'{}': {}", filename, e)),
+                _ => (),
+            }
+        }
+
         // Process this file's classes
         match root.find("classes") {
             Some(classes) => {
-                match self.process_classes(&classes) {
+                match self.process_classes(classes, filename, namespace) {
                     Err(e) => return Err(format!("'{}': {}", filename, e)),
                     _ => (),
                 }
@@ -86,10 +510,14 @@ impl<'a> Parser<'a> {
             None => (),  // No classes defined in this file
         }
 
-        // Process this file's templates
+        // Process this file's templates, which use the file's whitespace mode unless they set their
+        // own
         match root.find("templates") {
             Some(templates) => {
-                match self.process_templates(&templates) {
+                match self.process_templates(templates,
+                                             filename,
+                                             namespace,
+                                             root.get_attr("whitespace")) {
                     Err(e) => return Err(format!("'{}': {}", filename, e)),
                     _ => (),
                 }
@@ -100,21 +528,50 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    /// Loads a data file, binding its content to the name given by its `as` attribute.
+    fn process_data(&mut self,
+                    data_element: &elementtree::Element,
+                    dir: &std::path::Path)
+                    -> Result<(), String> {
+        let src = match data_element.get_attr("src") {
+            Some(src) => src,
+            None => return Err("Missing 'src' attribute in data".to_owned()),
+        };
+        let name = match data_element.get_attr("as") {
+            Some(name) => name,
+            None => return Err("Missing 'as' attribute in data".to_owned()),
+        };
+        let data_path = match self.project.find_file(&src.to_owned(), dir) {
+            Some(data_path) => data_path,
+            None => return Err(format!("Data file '{}' not found", src)),
+        };
+        let value = data::load(&data_path)?;
+        self.data.insert(name.to_owned(), data::to_values(&value));
+        Ok(())
+    }
+
     /// Processes all included files individually, adding their classes and templates.
     ///
-    // TODO(Lyrositor) This is probably vulnerable to circular dependencies.
+    /// An include with an `as` attribute places the included file's definitions in a nested
+    /// namespace, so that they are referenced as `alias:name`.
     fn process_includes(&mut self,
                         bbxml: &elementtree::Element,
-                        dir: &std::path::Path)
+                        dir: &std::path::Path,
+                        namespace: &str)
                         -> Result<(), String> {
         for include in bbxml.find_all("include") {
+            let include_namespace = match include.get_attr("as") {
+                Some(alias) => format!("{}{}:", namespace, alias),
+                None => namespace.to_owned(),
+            };
+
             // Ensure the attribute is there
             match include.get_attr("src") {
                 Some(src) => {
                     // Attempt to locate the file
                     match self.project.find_file(&src.to_owned(), dir) {
                         Some(path) => {
-                            match self.process_file(&path) {
+                            match self.process_file(&path, &include_namespace) {
                                 Err(e) => return Err(e),
                                 _ => (),
                             }
@@ -131,15 +588,35 @@ impl<'a> Parser<'a> {
 
     /// Processes a list of classes, storing their content in a more compact form.
     ///
-    /// No attempt is made to inspect the content of classes, so they could be any arbitrary
-    /// character data; only newlines are replaced (with whitespace).
-    fn process_classes(&mut self, classes: &elementtree::Element) -> Result<(), String> {
+    /// The content of classes is only parsed as a style once their inheritance is resolved.
+    /// A class can extend any number of other classes, listed in its `extends` attribute.
+    fn process_classes(&mut self,
+                       classes: &elementtree::Element,
+                       filename: &str,
+                       namespace: &str)
+                       -> Result<(), String> {
         for class in classes.find_all("class") {
             match class.get_attr("name") {
                 Some(name) => {
-                    match self.classes
+                    let name = &format!("{}{}", namespace, name);
+                    let previous = self.class_definitions
+                        .get(name)
+                        .map(|previous| previous.filename.clone());
+                    self.check_redefinition(class, "Class", name, previous, filename);
+                    let extends = class
+                        .get_attr("extends")
+                        .unwrap_or("")
+                        .split_whitespace()
+                        .map(|parent| parent.to_owned())
+                        .collect();
+                    match self.class_definitions
                               .insert(name.to_owned(),
-                                      Parser::compact_text(class.text(), " ")) {
+                                      ClassDefinition {
+                                          body: Parser::compact_text(class.text(), " "),
+                                          extends: extends,
+                                          filename: filename.to_owned(),
+                                          namespace: namespace.to_owned(),
+                                      }) {
                         _ => (),
                     }
                 }
@@ -149,12 +626,96 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    /// Warns if a definition replaces a previous one with the same name, unless it is explicitly
+    /// marked as an override.
+    fn check_redefinition(&mut self,
+                          definition: &elementtree::Element,
+                          kind: &str,
+                          name: &str,
+                          previous_filename: Option<String>,
+                          filename: &str) {
+        match previous_filename {
+            Some(ref previous_filename) if !Parser::is_flag_set(definition, "override") => {
+                self.warn(format!("'{}': {} '{}' replaces its definition from '{}' (add \
+                                   override=\"true\" if this is intended)",
+                                  filename,
+                                  kind,
+                                  name,
+                                  previous_filename))
+            }
+            _ => (),
+        }
+    }
+
+    /// Resolves a class's body by merging the styles of the classes it extends, in order, with its
+    /// own, each overriding the properties of the previous ones.
+    ///
+    /// Classes are only resolved, and checked, once they are used; their resolved body is kept for
+    /// later uses.
+    /// `chain` contains the classes currently being resolved, and is used to detect cycles.
+    fn resolve_class(&mut self, name: &str, chain: &mut Vec<String>) -> Result<String, String> {
+        match self.classes.get(name) {
+            Some(body) => return Ok(body.clone()),
+            None => (),
+        }
+        if chain.iter().any(|class| class == name) {
+            return Err(format!("Circular class inheritance: {} -> {}", chain.join(" -> "), name));
+        }
+
+        let (extends, own_body, namespace) = match self.class_definitions.get(name) {
+            Some(definition) => {
+                (definition.extends.clone(), definition.body.clone(), definition.namespace.clone())
+            }
+            None => return Err(format!("Class '{}' not found", name)),
+        };
+        chain.push(name.to_owned());
+        let mut style = css::Declarations::new();
+        for parent in extends {
+            let parent = Parser::qualify(&self.class_definitions, &namespace, &parent);
+            if !self.class_definitions.contains_key(&parent) {
+                return Err(format!("Class '{}' extends unknown class '{}'", name, parent));
+            }
+            // The parent's style was already checked when it was resolved
+            let parent_body = self.resolve_class(&parent, chain)?;
+            style.merge(css::Declarations::parse(&parent_body, &mut Vec::new()));
+        }
+        chain.pop();
+        style.merge(self.parse_style(&own_body, &format!("Class '{}'", name)));
+        let body = style.to_string();
+
+        self.classes.insert(name.to_owned(), body.clone());
+        Ok(body)
+    }
+
     /// Processes a list of templates, storing their content as an XML element.
-    fn process_templates(&mut self, templates: &elementtree::Element) -> Result<(), String> {
+    fn process_templates(&mut self,
+                         templates: &elementtree::Element,
+                         filename: &str,
+                         namespace: &str,
+                         whitespace: Option<&str>)
+                         -> Result<(), String> {
         for template in templates.find_all("template") {
             match template.get_attr("name") {
                 Some(name) => {
-                    match self.templates.insert(name.to_owned(), template.clone()) {
+                    let name = &format!("{}{}", namespace, name);
+                    let previous = self.templates
+                        .get(name)
+                        .map(|previous| previous.filename.clone());
+                    self.check_redefinition(template, "Template", name, previous, filename);
+                    let mut element = template.clone();
+                    match whitespace {
+                        Some(whitespace) if template.get_attr("whitespace").is_none() => {
+                            element.set_attr("whitespace", whitespace);
+                        }
+                        _ => (),
+                    }
+                    match self.templates
+                              .insert(name.to_owned(),
+                                      Template {
+                                          element: element,
+                                          filename: filename.to_owned(),
+                                          namespace: namespace.to_owned(),
+                                      }) {
                         _ => (),
                     }
                 }
@@ -164,82 +725,451 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    /// Resolves the inheritance of every template defined so far.
+    fn resolve_templates(&mut self) -> Result<(), String> {
+        let names: Vec<String> = self.templates.keys().cloned().collect();
+        for name in names {
+            self.resolve_template(&name, &mut Vec::new())?;
+        }
+        Ok(())
+    }
+
+    /// Resolves a template which extends another, replacing it by its base with the blocks it
+    /// overrides.
+    ///
+    /// The resolved template is parsed in the namespace of its base, while the blocks it overrides
+    /// are still parsed in its own.
+    /// `chain` contains the templates currently being resolved, and is used to detect cycles.
+    fn resolve_template(&mut self,
+                        name: &str,
+                        chain: &mut Vec<String>)
+                        -> Result<Template, String> {
+        let template = match self.templates.get(name) {
+            Some(template) => template.clone(),
+            None => return Err(format!("Template '{}' not found", name)),
+        };
+        let base_name = match template.element.get_attr("extends") {
+            Some(base_name) => Parser::qualify(&self.templates, &template.namespace, base_name),
+            None => return Ok(template),  // Nothing to resolve
+        };
+        if chain.iter().any(|template| template == name) {
+            return Err(format!("Circular template inheritance: {} -> {}",
+                               chain.join(" -> "),
+                               name));
+        }
+        if !self.templates.contains_key(&base_name) {
+            return Err(format!("'{}': Template '{}' extends unknown template '{}'",
+                               template.filename,
+                               name,
+                               base_name));
+        }
+
+        chain.push(name.to_owned());
+        let base = self.resolve_template(&base_name, chain)?;
+        chain.pop();
+        let source = format!("'{}', template '{}'", template.filename, name);
+        let resolved = Template {
+            element: self.extend_template(&base.element,
+                                          &template.element,
+                                          &template.namespace,
+                                          &source),
+            filename: template.filename,
+            namespace: base.namespace,
+        };
+        self.templates.insert(name.to_owned(), resolved.clone());
+        Ok(resolved)
+    }
+
+    /// Creates the element resulting from a template extending a base template.
+    ///
+    /// Each `<block>` of the base is replaced by the block of the same name in the template, if
+    /// there is one; the template's content outside of its blocks is ignored, and its blocks which
+    /// the base does not have are reported as warnings. The template's variables are added to those
+    /// of the base, overriding them.
+    /// The template's blocks and variables are marked with its namespace, in which they are parsed,
+    /// and its blocks keep its whitespace mode.
+    fn extend_template(&mut self,
+                       base: &elementtree::Element,
+                       template: &elementtree::Element,
+                       namespace: &str,
+                       source: &str)
+                       -> elementtree::Element {
+        let mut blocks = Vec::new();
+        Parser::find_blocks(template, &mut blocks);
+        match template.get_attr("whitespace") {
+            Some(whitespace) => {
+                for &mut (_, ref mut block) in &mut blocks {
+                    if block.get_attr("whitespace").is_none() {
+                        block.set_attr("whitespace", whitespace);
+                    }
+                }
+            }
+            None => (),
+        }
+        let blocks: std::collections::HashMap<String, elementtree::Element> = blocks
+            .into_iter()
+            .map(|(name, block)| (name, Parser::in_namespace(&block, namespace)))
+            .collect();
+        let mut extended = base.clone();
+        extended.remove_attr("extends");
+        let mut overridden = std::collections::HashSet::new();
+        Parser::override_blocks(&mut extended, &blocks, &mut overridden);
+        let mut missing: Vec<&String> = blocks
+            .keys()
+            .filter(|name| !overridden.contains(*name))
+            .collect();
+        missing.sort();
+        for name in missing {
+            self.warn(format!("{}: Block '{}' is not defined in the template it extends",
+                              source,
+                              name));
+        }
+
+        match template.find("vars") {
+            Some(vars) => {
+                if extended.find("vars").is_none() {
+                    extended.append_new_child("vars");
+                }
+                let index = (0..extended.child_count())
+                    .find(|&i| extended.get_child(i).unwrap().tag().name() == "vars")
+                    .unwrap();
+                let extended_vars = extended.get_child_mut(index).unwrap();
+                for var in vars.find_all("var") {
+                    extended_vars.append_child(Parser::in_namespace(var, namespace));
+                }
+            }
+            None => (),
+        }
+        extended
+    }
+
+    /// Finds the named blocks within an element, at any depth, along with their names.
+    ///
+    /// The blocks nested within a block are part of its content, and are not listed separately.
+    fn find_blocks(element: &elementtree::Element,
+                   blocks: &mut Vec<(String, elementtree::Element)>) {
+        for child in element.children() {
+            match child.get_attr("name") {
+                Some(name) if child.tag().name() == "block" => {
+                    blocks.push((name.to_owned(), child.clone()))
+                }
+                _ => Parser::find_blocks(child, blocks),
+            }
+        }
+    }
+
+    /// Replaces the named blocks within an element by the blocks of the same name, if any, adding
+    /// the names of the blocks replaced to `overridden`.
+    fn override_blocks(element: &mut elementtree::Element,
+                       blocks: &std::collections::HashMap<String, elementtree::Element>,
+                       overridden: &mut std::collections::HashSet<String>) {
+        for i in 0..element.child_count() {
+            let child = element.get_child_mut(i).unwrap();
+            let block = match child.get_attr("name") {
+                Some(name) if child.tag().name() == "block" => {
+                    blocks.get(name).map(|block| (name.to_owned(), block))
+                }
+                _ => None,
+            };
+            match block {
+                Some((name, block)) => {
+                    // The block's position within its parent's text is kept
+                    let tail = child.tail().to_owned();
+                    *child = block.clone();
+                    child.set_tail(tail);
+                    overridden.insert(name);
+                }
+                None => Parser::override_blocks(child, blocks, overridden),
+            }
+        }
+    }
+
+    /// Includes a template, binding the parameters passed to it.
+    ///
+    /// With dynamic scoping, the template also sees every parameter visible where it is included.
+    /// With lexical scoping, it only sees the global variables, its own variables, the parameters
+    /// passed to it and those listed in the include's `pass` attribute; parameters are then
+    /// rendered where they are passed, and any reference which dynamic scoping would have resolved
+    /// differently is reported as a warning.
+    fn parse_include<W: Write>(&mut self,
+                               include: &elementtree::Element,
+                               output: &mut W,
+                               replacements: &Replacements)
+                               -> Result<(), String> {
+        // Get the template's name
+        // The `template`'s abbreviation is `t`
+        let template_name = match include.get_attr("template") {
+            Some(name) => name,
+            None => {
+                match include.get_attr("t") {
+                    Some(name) => name,
+                    None => return Err("Missing 'template' attribute in include".to_owned()),
+                }
+            }
+        };
+
+        let template_name = &self.render_text(template_name, replacements)?;
+        let template_name = &Parser::qualify(&self.templates, &self.namespace, template_name);
+        let template = match self.templates.get(template_name) {
+                Some(template) => template,
+                None => {
+                    return Err(format!("{}: Template '{}' not found{}",
+                                       self.locate(),
+                                       template_name,
+                                       Parser::suggest(template_name, self.templates.keys())))
+                }
+            }
+            .clone();
+
+        // The template's own variables act as defaults for the include's parameters, but are
+        // still overridden by those defined on the command line
+        let mut template_vars = Replacements::new();
+        self.bind_namespace_vars(&template.namespace, &mut template_vars);
+        match template.element.find("vars") {
+            Some(vars) => {
+                for (name, value) in project::parse_vars(vars)? {
+                    let value = match self.project.defines.get(&name) {
+                        Some(define) => define.clone(),
+                        None => value,
+                    };
+                    template_vars.insert(name, vec![value]);
+                }
+            }
+            None => (),
+        }
+        let mut template_replacements = replacements.clone();
+        template_replacements.extend(template_vars.clone());
+        let mut include_replacements = Parser::bind_params(include,
+                                                           &template_replacements,
+                                                           &self.namespace)?;
+
+        let mut shadowed = std::collections::HashSet::new();
+        if self.project.lexical_scoping {
+            let passed: std::collections::HashSet<&str> = include
+                .find_all("param")
+                .chain(include.find_all("p"))
+                .filter_map(|param| param.get_attr("name"))
+                .collect();
+            let mut lexical_replacements = self.globals.clone();
+            lexical_replacements.extend(template_vars);
+            for name in include.get_attr("pass").unwrap_or("").split_whitespace() {
+                match replacements.get(name) {
+                    Some(values) => {
+                        lexical_replacements.insert(name.to_owned(), values.clone());
+                        ()
+                    }
+                    None => (),
+                }
+            }
+            for name in &passed {
+                // Data never refers to parameters, and is passed as it is to keep its fields
+                let mut values = Vec::new();
+                for value in &include_replacements[*name] {
+                    if value.get_attr("data-type").is_some() {
+                        values.push(value.clone());
+                    } else {
+                        values.push(self.render_value(value, replacements)?);
+                    }
+                }
+                lexical_replacements.insert((*name).to_owned(), values);
+            }
+
+            // Find the parameters which dynamic scoping would have resolved differently
+            for (name, values) in &include_replacements {
+                let differs = match lexical_replacements.get(name) {
+                    Some(lexical_values) => {
+                        !passed.contains(name.as_str()) &&
+                        !Parser::same_values(lexical_values, values)
+                    }
+                    None => true,
+                };
+                if differs {
+                    shadowed.insert(name.clone());
+                }
+            }
+            include_replacements = lexical_replacements;
+        }
+
+        let location = std::mem::replace(&mut self.location,
+                                         format!("'{}', template '{}'",
+                                                 template.filename,
+                                                 template_name));
+        let element_path = std::mem::take(&mut self.element_path);
+        let namespace = std::mem::replace(&mut self.namespace, template.namespace.clone());
+        let shadowed = std::mem::replace(&mut self.shadowed, shadowed);
+        self.parse_element(&template.element, output, &include_replacements)?;
+        self.location = location;
+        self.element_path = element_path;
+        self.namespace = namespace;
+        self.shadowed = shadowed;
+        Ok(())
+    }
+
     /// Main parsing function, parses an XML element to convert it to BBCode.
-    fn parse_element(&mut self,
-                     element: &elementtree::Element,
-                     output: &mut std::io::BufWriter<std::fs::File>,
-                     replacements: &std::collections::HashMap<String, elementtree::Element>)
-                     -> Result<(), String> {
-        // Check if this element is plain or not
-        let compact = match element.get_attr("plain") {
-            Some(plain) => {
-                match plain {
-                    "true" => false,
-                    "1" => false,
-                    _ => true,
-                }
-            }
-            None => true,
+    ///
+    /// The whitespace mode set by the element only applies to it and its descendants, and is
+    /// restored once it is parsed, whether parsing succeeds or not.
+    fn parse_element<W: Write>(&mut self,
+                               element: &elementtree::Element,
+                               output: &mut W,
+                               replacements: &Replacements)
+                               -> Result<(), String> {
+        let whitespace = self.whitespace;
+        let result = self.parse_element_content(element, output, replacements);
+        self.whitespace = whitespace;
+        result
+    }
+
+    /// Parses an XML element to convert it to BBCode, setting the whitespace mode of its
+    /// descendants.
+    fn parse_element_content<W: Write>(&mut self,
+                                       element: &elementtree::Element,
+                                       output: &mut W,
+                                       replacements: &Replacements)
+                                       -> Result<(), String> {
+        // Check if this element is plain or not, and how its text must be escaped
+        // Plain text is not escaped either, unless explicitly requested; raw text never is
+        // The whitespace mode is inherited by the element's descendants, but `plain` only applies
+        // to its own text
+        let plain = Parser::is_flag_set(element, "plain");
+        match element.get_attr("whitespace") {
+            Some(name) => {
+                self.whitespace = match whitespace::Whitespace::from_name(name) {
+                    Some(whitespace) => whitespace,
+                    None => return Err(format!("Unknown whitespace mode '{}'", name)),
+                }
+            }
+            None => (),
+        }
+        let whitespace = if plain {
+            whitespace::Whitespace::Preserve
+        } else {
+            self.whitespace
         };
+        let escape = match element.get_attr("escape") {
+            _ if Parser::is_flag_set(element, "raw") => dialect::Escape::None,
+            Some("true") | Some("1") => self.escape,
+            Some("false") | Some("0") => dialect::Escape::None,
+            Some(name) => {
+                match dialect::Escape::from_name(name) {
+                    Some(escape) => escape,
+                    None => return Err(format!("Unknown escaping strategy '{}'", name)),
+                }
+            }
+            None if !plain => self.escape,
+            None => dialect::Escape::None,
+        };
+
+        self.output_text(element.text(), output, replacements, whitespace, escape)?;
 
-        self.output_text(element.text(), output, replacements, compact)?;
+        // Bindings made with `<let>` are visible to the following siblings and their descendants
+        // Their content is rendered once, where they are bound, and then reused as it is
+        let mut local_replacements: Option<Replacements> = None;
+        let mut positions: std::collections::HashMap<&str, usize> =
+            std::collections::HashMap::new();
+        let mut unshadowed: Vec<String> = Vec::new();
         for child in element.children() {
+            // Keep track of the element's position among its siblings, to locate it in messages
+            let position = positions.entry(child.tag().name()).or_insert(0);
+            *position += 1;
+            self.element_path.push(format!("{}[{}]", child.tag().name(), position));
+
+            if child.tag().name() == "let" {
+                let name = match child.get_attr("name") {
+                    Some(name) => name,
+                    None => {
+                        return Err(format!("{}: Missing 'name' attribute in let", self.locate()))
+                    }
+                };
+                let mut scope = local_replacements
+                    .take()
+                    .unwrap_or_else(|| replacements.clone());
+                let value = self.render_value(child, &scope)?;
+                scope.insert(name.to_owned(), vec![value]);
+                if self.shadowed.remove(name) {
+                    unshadowed.push(name.to_owned());
+                }
+                self.element_path.pop();
+                self.output_text(child.tail(), output, &scope, whitespace, escape)?;
+                local_replacements = Some(scope);
+                continue;
+            }
+            let replacements = match local_replacements {
+                Some(ref scope) => scope,
+                None => replacements,
+            };
+
             let name = match child.tag().name() {
                 "d" => "div",
                 "in" => "include",
                 _ => child.tag().name(),
             };
             match name {
+                "vars" => (),  // Variables are bound when the template is included
+                // Blocks overridden by an extending template are parsed in its namespace
+                "block" => self.parse_value(child, output, replacements)?,
                 "br" => {
+                    self.end_block();
                     match output.write("\n".as_bytes()) {
                         Err(e) => return Err(format!("Failed to write to output: {}", e)),
                         _ => (),
                     }
                 }
-                "include" => {
-                    // Get the template's name
-                    // The `template`'s abbreviation is `t`
-                    let template_name = match child.get_attr("template") {
-                        Some(name) => name,
-                        None => {
-                            match child.get_attr("t") {
-                                Some(name) => name,
-                                None => {
-                                    return Err(format!("Missing 'template' attribute in include"));
-                                }
-                            }
-                        }
+                "include" => self.parse_include(child, output, replacements)?,
+                "for" => {
+                    // Get the loop's variable and the list it iterates over
+                    let variable = match child.get_attr("each") {
+                        Some(variable) => variable,
+                        None => return Err("Missing 'each' attribute in for".to_owned()),
+                    };
+                    let list_name = match child.get_attr("in") {
+                        Some(list_name) => list_name,
+                        None => return Err("Missing 'in' attribute in for".to_owned()),
                     };
+                    let index_name = child.get_attr("index").unwrap_or("index");
 
-                    let mut include_replacements:
-                    std::collections::HashMap<String, elementtree::Element> =
-                        replacements.clone();
-                    for param in child.find_all("param").chain(child.find_all("p")) {
-                        match param.get_attr("name") {
-                            Some(name) => {
-                                include_replacements.insert(name.to_owned(), param.clone());
-                                ()
-                            }
-                            None => return Err("Missing 'name' attribute in param".to_owned()),
-                        }
+                    // An unbound list is treated as empty, so optional lists can be left out
+                    let items = match Parser::lookup(replacements, list_name) {
+                        Some(items) => items,
+                        None => Vec::new(),
+                    };
+                    let unshadowed: Vec<String> = [variable, index_name]
+                        .iter()
+                        .filter(|name| self.shadowed.remove(**name))
+                        .map(|name| (*name).to_owned())
+                        .collect();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let mut loop_replacements = replacements.clone();
+                        loop_replacements.insert(variable.to_owned(), vec![item]);
+                        loop_replacements.insert(index_name.to_owned(),
+                                                 vec![Parser::text_element(&(i + 1).to_string())]);
+                        self.parse_element(child, output, &loop_replacements)?;
                     }
-                    let template = match self.templates.get(template_name) {
-                            Some(template) => template,
-                            None => return Err(format!("Template '{}' not found", template_name)),
-                        }
-                        .clone();
-                    self.parse_element(&template, output, &include_replacements)?;
+                    self.shadowed.extend(unshadowed);
                 }
                 "li" => {
+                    self.end_block();
                     match write!(output, "[*]") {
                         Err(e) => return Err(format!("Failed to write to output: {}", e)),
                         _ => (),
                     }
+                    self.end_block();
                     self.parse_element(child, output, replacements)?;
                 }
                 _ => {
+                    // A `<tag>` element outputs a tag named by its `name` attribute, which is
+                    // written as it is rather than handled as a BBXML element
+                    let tag_name: String;
+                    let name = match name {
+                        "tag" => {
+                            tag_name = self.render_tag_name(child, replacements)?;
+                            &tag_name
+                        }
+                        _ => name,
+                    };
+
                     // Craft the tag's option attribute
-                    let mut options: Vec<String> = Vec::new();
+                    let mut class_bodies: Vec<(ClassReference, String)> = Vec::new();
 
                     // Get this element's classes, if any
                     // The `class` attribute's abbreviation is `c`
@@ -252,36 +1182,104 @@ impl<'a> Parser<'a> {
                             }
                         }
                     };
-                    for class in classes.split_whitespace() {
-                        match self.classes.get(class) {
-                            Some(class_body) => options.push(class_body.clone()),
-                            None => (),
-                        };
+                    let classes = &self.render_text(classes, replacements)?;
+                    for mut class in Parser::parse_class_list(classes)? {
+                        class.name = Parser::qualify(&self.class_definitions,
+                                                     &self.namespace,
+                                                     &class.name);
+                        if self.class_definitions.contains_key(&class.name) {
+                            let class_body = self.resolve_class(&class.name, &mut Vec::new())?;
+                            class_bodies.push((class, class_body));
+                            continue;
+                        }
+
+                        // Unknown classes are only errors in strict mode
+                        let message = format!("{}: Unknown class '{}'{}",
+                                              self.locate(),
+                                              class.name,
+                                              Parser::suggest(&class.name,
+                                                              self.class_definitions.keys()));
+                        if self.project.strict {
+                            return Err(message);
+                        }
+                        self.warn(message);
                     }
 
                     // Get this element's option, if specified
                     // The `option` attribute's abbreviation is `o`
-                    match child.get_attr("option") {
-                        Some(option) => options.push(option.to_owned()),
+                    let option = match child.get_attr("option") {
+                        Some(option) => Some(self.render_text(option.trim(), replacements)?),
                         None => {
                             match child.get_attr("o") {
-                                Some(option) => options.push(option.to_owned()),
-                                None => (),
+                                Some(option) => Some(self.render_text(option.trim(), replacements)?),
+                                None => None,
+                            }
+                        }
+                    };
+
+                    // Classes are merged with the option as styles, each overriding the previous
+                    // ones; otherwise, the option need not be a style and is kept as is
+                    // Classes are checked again once their parameters are replaced
+                    let is_style = STYLE_TAGS.contains(&name);
+                    let option = if !class_bodies.is_empty() || (is_style && self.minify) {
+                        let mut style = css::Declarations::new();
+                        for (class, class_body) in class_bodies {
+                            // A library's classes refer to its own variables, like its templates
+                            let mut class_replacements = replacements.clone();
+                            let class_namespace =
+                                self.class_definitions[&class.name].namespace.clone();
+                            self.bind_namespace_vars(&class_namespace, &mut class_replacements);
+
+                            // A class's arguments are only visible within its own body
+                            for (param, value) in class.args {
+                                class_replacements.insert(param,
+                                                          vec![Parser::text_element(&value)]);
+                            }
+                            let class_body = self.render_text(&class_body, &class_replacements)?;
+                            style.merge(self.parse_style(&class_body,
+                                                         &format!("Class '{}'", class.name)));
+                        }
+                        match option {
+                            Some(option) => {
+                                let source = format!("{}: Option of '{}'", self.locate(), name);
+                                style.merge(self.parse_style(&option, &source))
+                            }
+                            None => (),
+                        }
+                        if self.minify {
+                            style.to_minified_string()
+                        } else {
+                            style.to_string()
+                        }
+                    } else {
+                        match option {
+                            Some(ref option) if is_style => {
+                                let source = format!("{}: Option of '{}'", self.locate(), name);
+                                self.parse_style(option, &source);
                             }
+                            _ => (),
                         }
+                        option.unwrap_or_default()
                     };
 
                     // Create the opening tag
+                    // Whitespace before an inline tag is kept outside of it, and dropped around
+                    // a block tag
+                    let block = whitespace::is_block_tag(name);
+                    if block {
+                        self.end_block();
+                    } else {
+                        self.write_pending_space(output)?;
+                    }
                     match write!(output, "[{}", name.to_uppercase()) {
                         Err(e) => return Err(format!("Failed to write to output: {}", e)),
                         _ => (),
                     }
-                    if !options.is_empty() {
-                        match write!(output, "=") {
+                    if !option.is_empty() {
+                        match write!(output, "={}", option) {
                             Err(e) => return Err(format!("Failed to write to output: {}", e)),
                             _ => (),
                         }
-                        self.output_text(options.join("").trim(), output, replacements, false)?;
                     }
                     match write!(output, "]") {
                         Err(e) => return Err(format!("Failed to write to output: {}", e)),
@@ -289,15 +1287,26 @@ impl<'a> Parser<'a> {
                     }
 
                     // Write the content of the element and any text that immediately follows it
+                    if block {
+                        self.end_block();
+                    }
                     self.parse_element(child, output, replacements)?;
+                    if block {
+                        self.end_block();
+                    }
                     match write!(output, "[/{}]", name.to_uppercase()) {
                         Err(e) => return Err(format!("Failed to write to output: {}", e)),
                         _ => (),
                     }
+                    if block {
+                        self.end_block();
+                    }
                 }
             }
-            self.output_text(child.tail(), output, replacements, compact)?;
+            self.element_path.pop();
+            self.output_text(child.tail(), output, replacements, whitespace, escape)?;
         }
+        self.shadowed.extend(unshadowed);
 
         Ok(())
     }
@@ -305,60 +1314,538 @@ impl<'a> Parser<'a> {
     /// Outputs a text string, formatting it and replacing template parameters as required.
     ///
     /// Newlines, indentation and extra spaces on the end of lines are deleted.
-    fn output_text(&mut self,
-                   text: &str,
-                   output: &mut std::io::BufWriter<std::fs::File>,
-                   replacements: &std::collections::HashMap<String, elementtree::Element>,
-                   compact: bool)
-                   -> Result<(), String> {
-        let replacements_re = regex::Regex::new(r"\{([\w-]+)\}").unwrap();
-        let mut formatted_text = text.to_owned();
-        if compact {
-            formatted_text = Parser::compact_text(text, "");
-        }
-        match replacements_re.captures(&formatted_text) {
-            Some(params) => {
-                let normal_texts = replacements_re.split(&formatted_text);
-                let mut i = 1;
-                for normal_text in normal_texts {
-                    match output.write(normal_text.as_bytes()) {
-                        Err(e) => return Err(format!("Failed to write to output: {}", e)),
-                        _ => (),
-                    }
-                    match params.get(i) {
-                        Some(param) => {
-                            match replacements.get(param.as_str()) {
-                                Some(replacement) => {
-                                    self.parse_element(replacement,
-                                                       output,
-                                                       replacements)?;
-                                    ()
-                                }
-                                None => {
-                                    match write!(output, "{{{}}}", param.as_str()) {
-                                        Err(e) => {
-                                            return Err(format!("Failed to write to output: {}", e))
-                                        }
-                                        _ => (),
-                                    }
-                                }
-                            }
-                        }
-                        None => (),
+    /// `{{` and `}}` are written as literal braces, and the text itself (but not its replacements)
+    /// is escaped with the specified strategy so it cannot open a BBCode tag.
+    fn output_text<W: Write>(&mut self,
+                             text: &str,
+                             output: &mut W,
+                             replacements: &Replacements,
+                             whitespace: whitespace::Whitespace,
+                             escape: dialect::Escape)
+                             -> Result<(), String> {
+        let replacements_re = regex::Regex::new(r"\{\{|\}\}|\{([\w:-]+(?:\.[\w-]+)*)\}").unwrap();
+        let formatted_text = match whitespace {
+            whitespace::Whitespace::Compact => Parser::compact_text(text, ""),
+            whitespace::Whitespace::PreserveLines => {
+                let indentation_re = regex::Regex::new(r"[ \t]*(\r?\n)[ \t]*").unwrap();
+                indentation_re.replace_all(text, "$1").into_owned()
+            }
+            whitespace::Whitespace::Trim => text.trim().to_owned(),
+            _ => text.to_owned(),
+        };
+        let mut last_end = 0;
+        for params in replacements_re.captures_iter(&formatted_text) {
+            let placeholder = params.get(0).unwrap();
+            self.write_text(&formatted_text[last_end..placeholder.start()],
+                            output,
+                            whitespace,
+                            escape)?;
+            last_end = placeholder.end();
+
+            // Escaped braces are written once, as they are
+            let param = match params.get(1) {
+                Some(param) => param.as_str(),
+                None => {
+                    self.write_text(&placeholder.as_str()[..1],
+                                    output,
+                                    whitespace,
+                                    dialect::Escape::None)?;
+                    continue;
+                }
+            };
+            // Fields of data are referred to as `{name.field}`, and shadowed along with their data
+            let base_name = param.split('.').next().unwrap();
+            if self.shadowed.contains(base_name) {
+                let message = if replacements.contains_key(base_name) {
+                    format!("{}: '{}' refers to a global variable, but the caller's '{}' would \
+                             have been used with dynamic scoping",
+                            self.locate(),
+                            base_name,
+                            base_name)
+                } else {
+                    format!("{}: '{}' is not passed to this template, but the caller's would have \
+                             been used with dynamic scoping",
+                            self.locate(),
+                            base_name)
+                };
+                self.warn(message);
+            }
+            match Parser::lookup(replacements, param) {
+                Some(values) => {
+                    for value in &values {
+                        self.parse_value(value, output, replacements)?;
                     }
-                    i += 1;
+                }
+                None => {
+                    self.write_text(&format!("{{{}}}", param),
+                                    output,
+                                    whitespace,
+                                    dialect::Escape::None)?;
                 }
             }
-            None => {
-                match output.write(formatted_text.as_bytes()) {
-                    Err(e) => return Err(format!("Failed to write to output: {}", e)),
-                    _ => (),
+        }
+        self.write_text(&formatted_text[last_end..], output, whitespace, escape)
+    }
+
+    /// Writes literal text to the output, escaping it and handling its whitespace.
+    ///
+    /// When whitespace is collapsed, the whitespace at either end of the text is only written
+    /// once some content follows it, and never at the start of a block or after another space.
+    fn write_text<W: Write>(&mut self,
+                            text: &str,
+                            output: &mut W,
+                            whitespace: whitespace::Whitespace,
+                            escape: dialect::Escape)
+                            -> Result<(), String> {
+        let text = match whitespace {
+            whitespace::Whitespace::Collapse => {
+                if text.starts_with(char::is_whitespace) {
+                    self.pending_space = true;
                 }
+                let collapsed = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+                if collapsed.is_empty() {
+                    return Ok(());
+                }
+                self.write_pending_space(output)?;
+                if text.ends_with(char::is_whitespace) {
+                    self.pending_space = true;
+                }
+                collapsed
             }
+            _ => {
+                if text.is_empty() {
+                    return Ok(());
+                }
+                self.write_pending_space(output)?;
+                text.to_owned()
+            }
+        };
+        match write!(output, "{}", escape.apply(&text)) {
+            Err(e) => return Err(format!("Failed to write to output: {}", e)),
+            _ => (),
+        }
+        self.skip_space = false;
+        Ok(())
+    }
+
+    /// Writes the space left pending by collapsed whitespace, unless at the start of a block or
+    /// right after another space.
+    fn write_pending_space<W: Write>(&mut self, output: &mut W) -> Result<(), String> {
+        if self.pending_space && !self.skip_space {
+            match write!(output, " ") {
+                Err(e) => return Err(format!("Failed to write to output: {}", e)),
+                _ => (),
+            }
+            // Whitespace right after it, such as at the start of an inline tag, is part of it
+            self.skip_space = true;
         }
+        self.pending_space = false;
         Ok(())
     }
 
+    /// Marks a block boundary, where pending whitespace is dropped.
+    fn end_block(&mut self) {
+        self.pending_space = false;
+        self.skip_space = true;
+    }
+
+    /// Finds the values bound to a parameter, which may be a field of data such as `stats.str`.
+    fn lookup(replacements: &Replacements, name: &str) -> Option<Vec<elementtree::Element>> {
+        match replacements.get(name) {
+            Some(values) => return Some(values.clone()),
+            None => (),
+        }
+        let mut parts = name.split('.');
+        let mut values = match replacements.get(parts.next().unwrap()) {
+            Some(values) => values.clone(),
+            None => return None,
+        };
+        for part in parts {
+            values = match values.len() {
+                1 => data::field(&values[0], part)?,
+                _ => return None,
+            };
+        }
+        Some(values)
+    }
+
+    /// Parses a style, reporting any problem found in it as a warning.
+    ///
+    /// `source` describes where the style comes from, to locate it in warnings.
+    fn parse_style(&mut self, style: &str, source: &str) -> css::Declarations {
+        let mut problems: Vec<String> = Vec::new();
+        let declarations = css::Declarations::parse(style, &mut problems);
+        for problem in problems {
+            self.warn(format!("{}: {}", source, problem));
+        }
+        declarations
+    }
+
+    /// Reports a warning, only once per distinct message.
+    fn warn(&mut self, message: String) {
+        if self.warnings.insert(message.clone()) {
+            println!("WARNING: {}", message);
+        }
+    }
+
+    /// Describes the location of the element being parsed, for use in messages.
+    ///
+    /// The element is located by the tag and position among its siblings of the same tag of each
+    /// of its ancestors, e.g. `'main.xml', body/div[2]/span[1]`.
+    fn locate(&self) -> String {
+        let mut location = self.location.clone();
+        for element in &self.element_path {
+            location.push('/');
+            location.push_str(element);
+        }
+        location
+    }
+
+    /// Suggests the closest known name to a misspelled one, as a hint to append to a message.
+    ///
+    /// A name is close enough if at most a third of its characters, and never fewer than two, need
+    /// to be edited. Returns an empty string if no known name is close enough.
+    fn suggest<'n, I: Iterator<Item = &'n String>>(name: &str, known_names: I) -> String {
+        let closest = known_names
+            .map(|known_name| (Parser::edit_distance(name, known_name), known_name))
+            .filter(|&(distance, known_name)| {
+                        let length = std::cmp::max(name.chars().count(),
+                                                   known_name.chars().count());
+                        distance <= std::cmp::max(2, length / 3)
+                    })
+            .min();
+        match closest {
+            Some((_, known_name)) => format!(" (did you mean '{}'?)", known_name),
+            None => String::new(),
+        }
+    }
+
+    /// Computes the edit distance between two strings, counting insertions, deletions,
+    /// substitutions and transpositions of adjacent characters (as in `bxo` for `box`).
+    fn edit_distance(a: &str, b: &str) -> usize {
+        let a: Vec<char> = a.chars().collect();
+        let b: Vec<char> = b.chars().collect();
+        // distances[i][j] is the distance between the first i characters of a and the first j of b
+        let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+        distances[0] = (0..b.len() + 1).collect();
+        for (i, row) in distances.iter_mut().enumerate() {
+            row[0] = i;
+        }
+        for i in 1..a.len() + 1 {
+            for j in 1..b.len() + 1 {
+                let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+                let substitution = distances[i - 1][j - 1] + cost;
+                let mut distance = *[distances[i - 1][j] + 1, distances[i][j - 1] + 1, substitution]
+                                        .iter()
+                                        .min()
+                                        .unwrap();
+                if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                    distance = std::cmp::min(distance, distances[i - 2][j - 2] + 1);
+                }
+                distances[i][j] = distance;
+            }
+        }
+        distances[a.len()][b.len()]
+    }
+
+    /// Renders a text string without compacting or escaping it, replacing template parameters.
+    fn render_text(&mut self, text: &str, replacements: &Replacements) -> Result<String, String> {
+        let mut rendered: Vec<u8> = Vec::new();
+        let state = (self.pending_space, self.skip_space);
+        self.pending_space = false;
+        self.output_text(text,
+                         &mut rendered,
+                         replacements,
+                         whitespace::Whitespace::Preserve,
+                         dialect::Escape::None)?;
+        let (pending_space, skip_space) = state;
+        self.pending_space = pending_space;
+        self.skip_space = skip_space;
+        match String::from_utf8(rendered) {
+            Ok(rendered) => Ok(rendered),
+            Err(e) => Err(format!("Invalid text: {}", e)),
+        }
+    }
+
+    /// Parses a value bound to a parameter or variable, within the namespace it was defined in.
+    ///
+    /// Classes and templates used in the content of a parameter are thus those of its caller, and
+    /// not those of the template it is passed to.
+    fn parse_value<W: Write>(&mut self,
+                             value: &elementtree::Element,
+                             output: &mut W,
+                             replacements: &Replacements)
+                             -> Result<(), String> {
+        let namespace = match value.get_attr("namespace") {
+            Some(namespace) => std::mem::replace(&mut self.namespace, namespace.to_owned()),
+            None => return self.parse_element(value, output, replacements),
+        };
+        let result = self.parse_element(value, output, replacements);
+        self.namespace = namespace;
+        result
+    }
+
+    /// Marks a value with the namespace it was defined in, in which it is later parsed.
+    fn in_namespace(value: &elementtree::Element, namespace: &str) -> elementtree::Element {
+        let mut value = value.clone();
+        value.set_attr("namespace", namespace);
+        value
+    }
+
+    /// Binds the variables of the files in a namespace, and in the namespaces enclosing it, without
+    /// their prefix, so that the templates of a library refer to its own variables.
+    ///
+    /// Variables defined on the command line still override them.
+    fn bind_namespace_vars(&self, namespace: &str, replacements: &mut Replacements) {
+        // The variables of the global namespace are already bound everywhere
+        for (end, _) in namespace.match_indices(':') {
+            let prefix = &namespace[..end + 1];
+            for (name, value) in &self.vars {
+                if name.starts_with(prefix) && !name[prefix.len()..].contains(':') {
+                    let name = &name[prefix.len()..];
+                    let value = match self.project.defines.get(name) {
+                        Some(define) => define.clone(),
+                        None => value.clone(),
+                    };
+                    replacements.insert(name.to_owned(), vec![value]);
+                }
+            }
+        }
+    }
+
+    /// Renders a value where it is bound, so that it can be reused as it is in any scope.
+    fn render_value(&mut self,
+                    value: &elementtree::Element,
+                    replacements: &Replacements)
+                    -> Result<elementtree::Element, String> {
+        // Its whitespace is handled as if it were a block of its own
+        let mut rendered: Vec<u8> = Vec::new();
+        let state = (self.pending_space, self.skip_space);
+        self.end_block();
+        self.parse_value(value, &mut rendered, replacements)?;
+        let (pending_space, skip_space) = state;
+        self.pending_space = pending_space;
+        self.skip_space = skip_space;
+        let rendered = match String::from_utf8(rendered) {
+            Ok(rendered) => rendered,
+            Err(e) => return Err(format!("Invalid text: {}", e)),
+        };
+
+        // The rendered value must not be formatted again when it is output
+        let mut rendered_value = Parser::text_element(&rendered.replace("{", "{{")
+                                                          .replace("}", "}}"));
+        rendered_value.set_attr("plain", "true");
+        rendered_value.set_attr("raw", "true");
+        Ok(rendered_value)
+    }
+
+    /// Binds the parameters passed to an include on top of the caller's replacements.
+    ///
+    /// Repeating a parameter, or giving it `<item>` children, binds it to a list of values.
+    /// A parameter which only refers to data or to a list, such as `{stats}`, is bound to the values
+    /// it refers to, so that their fields and items remain accessible.
+    /// Each value passed is marked with the caller's namespace, in which it is later parsed.
+    fn bind_params(include: &elementtree::Element,
+                   replacements: &Replacements,
+                   namespace: &str)
+                   -> Result<Replacements, String> {
+        let mut include_replacements = replacements.clone();
+        let mut bound: std::collections::HashSet<String> = std::collections::HashSet::new();
+        for param in include.find_all("param").chain(include.find_all("p")) {
+            let name = match param.get_attr("name") {
+                Some(name) => name,
+                None => return Err("Missing 'name' attribute in param".to_owned()),
+            };
+
+            // The first value bound in this include replaces the caller's value
+            if bound.insert(name.to_owned()) {
+                include_replacements.insert(name.to_owned(), Vec::new());
+            }
+            let referenced = Parser::referenced_values(param, replacements);
+            let values = include_replacements.get_mut(name).unwrap();
+            if param.find("item").is_some() {
+                values.extend(param
+                                  .find_all("item")
+                                  .map(|item| Parser::in_namespace(item, namespace)));
+            } else {
+                match referenced {
+                    Some(referenced) => values.extend(referenced),
+                    None => values.push(Parser::in_namespace(param, namespace)),
+                }
+            }
+        }
+        Ok(include_replacements)
+    }
+
+    /// Finds the name of the tag output by a `<tag>` element, replacing the parameters in its
+    /// `name` attribute.
+    ///
+    /// The name must be a valid BBCode tag name, and cannot be that of a BBXML element.
+    fn render_tag_name(&mut self,
+                       element: &elementtree::Element,
+                       replacements: &Replacements)
+                       -> Result<String, String> {
+        let name = match element.get_attr("name") {
+            Some(name) => self.render_text(name, replacements)?,
+            None => return Err(format!("{}: Missing 'name' attribute in tag", self.locate())),
+        };
+        let mut chars = name.chars();
+        let is_valid = match chars.next() {
+            Some(first) => {
+                (first.is_ascii_alphabetic() || first == '*') &&
+                chars.all(|c| c.is_ascii_alphanumeric())
+            }
+            None => false,
+        };
+        if !is_valid {
+            return Err(format!("{}: Invalid tag name '{}'", self.locate(), name));
+        }
+        if RESERVED_TAGS.contains(&name.as_str()) {
+            return Err(format!("{}: Reserved tag name '{}'", self.locate(), name));
+        }
+        Ok(name)
+    }
+
+    /// Finds the values a parameter refers to, if its content is a single reference to data or to a
+    /// list.
+    fn referenced_values(param: &elementtree::Element,
+                         replacements: &Replacements)
+                         -> Option<Vec<elementtree::Element>> {
+        let reference_re = regex::Regex::new(r"^\{([\w:-]+(?:\.[\w-]+)*)\}$").unwrap();
+        if param.child_count() > 0 {
+            return None;
+        }
+        let name = reference_re.captures(param.text().trim())?.get(1).unwrap().as_str();
+        let values = Parser::lookup(replacements, name)?;
+        if values.len() != 1 || values[0].get_attr("data-type").is_some() {
+            Some(values)
+        } else {
+            None
+        }
+    }
+
+    /// Parses a list of classes, such as `box(color=red, width=50%) wide`, into the name of each
+    /// class and the arguments passed to it.
+    fn parse_class_list(classes: &str) -> Result<Vec<ClassReference>, String> {
+        let mut class_list = Vec::new();
+        for class in Parser::split_outside_parentheses(classes, char::is_whitespace) {
+            let class = class.trim();
+            if class.is_empty() {
+                continue;
+            }
+            let (name, args) = match class.find('(') {
+                Some(i) if class.ends_with(')') => (&class[..i], &class[i + 1..class.len() - 1]),
+                Some(_) => return Err(format!("Unterminated arguments in class '{}'", class)),
+                None => (class, ""),
+            };
+
+            let mut parsed_args = Vec::new();
+            for arg in Parser::split_outside_parentheses(args, |c| c == ',') {
+                if arg.trim().is_empty() {
+                    continue;
+                }
+                let mut parts = arg.splitn(2, '=');
+                let param = parts.next().unwrap().trim();
+                match parts.next() {
+                    Some(value) if !param.is_empty() => {
+                        parsed_args.push((param.to_owned(), value.trim().to_owned()))
+                    }
+                    _ => {
+                        return Err(format!("Invalid argument '{}' in class '{}', expected \
+                                            'name=value'",
+                                           arg.trim(),
+                                           name))
+                    }
+                }
+            }
+            class_list.push(ClassReference {
+                                name: name.to_owned(),
+                                args: parsed_args,
+                            });
+        }
+        Ok(class_list)
+    }
+
+    /// Splits a string on every separator that is not within parentheses.
+    fn split_outside_parentheses<F: Fn(char) -> bool>(text: &str, is_separator: F) -> Vec<&str> {
+        let mut parts = Vec::new();
+        let mut depth = 0;
+        let mut start = 0;
+        for (i, c) in text.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                _ if depth == 0 && is_separator(c) => {
+                    parts.push(&text[start..i]);
+                    start = i + c.len_utf8();
+                }
+                _ => (),
+            }
+        }
+        parts.push(&text[start..]);
+        parts
+    }
+
+    /// Finds the full name of a class or template referenced from within a namespace.
+    ///
+    /// Definitions from the namespace itself take precedence over those with the same name in the
+    /// enclosing namespaces, down to the global one.
+    fn qualify<T>(definitions: &std::collections::HashMap<String, T>,
+                  namespace: &str,
+                  name: &str)
+                  -> String {
+        let mut prefix = namespace;
+        while !prefix.is_empty() {
+            let qualified_name = format!("{}{}", prefix, name);
+            if definitions.contains_key(&qualified_name) {
+                return qualified_name;
+            }
+            // Strip the innermost alias, e.g. `ui:icons:` becomes `ui:`
+            prefix = match prefix[..prefix.len() - 1].rfind(':') {
+                Some(i) => &prefix[..i + 1],
+                None => "",
+            };
+        }
+        name.to_owned()
+    }
+
+    /// Checks whether two lists of values are identical, comparing their elements recursively.
+    fn same_values(a: &[elementtree::Element], b: &[elementtree::Element]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(a, b)| Parser::same_element(a, b))
+    }
+
+    /// Checks whether two elements have the same tag, attributes, text and children.
+    fn same_element(a: &elementtree::Element, b: &elementtree::Element) -> bool {
+        a.tag() == b.tag() && a.text() == b.text() && a.tail() == b.tail() &&
+        a.attr_count() == b.attr_count() &&
+        a.attrs().all(|(name, value)| b.get_attr(name) == Some(value)) &&
+        a.child_count() == b.child_count() &&
+        a.children().zip(b.children()).all(|(a, b)| Parser::same_element(a, b))
+    }
+
+    /// Checks whether a boolean attribute of an element is set to `true` (or `1`).
+    fn is_flag_set(element: &elementtree::Element, attr: &str) -> bool {
+        match element.get_attr(attr) {
+            Some("true") | Some("1") => true,
+            _ => false,
+        }
+    }
+
+    /// Creates an element containing only the specified text, for use as a replacement value.
+    fn text_element(text: &str) -> elementtree::Element {
+        let mut element = elementtree::Element::new("param");
+        element.set_text(text);
+        element
+    }
+
+    /// Converts a name to one which can safely be used in a file name.
+    fn file_name(name: &str) -> String {
+        name.trim()
+            .chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+            .collect()
+    }
+
     /// Replaces all newlines (including preceding and succeeding whitespace) with a replacement.
     fn compact_text(text: &str, replacement: &str) -> String {
         let spaces_re = regex::Regex::new(r"(?:\s*(?:\r?\n)\s*)+").unwrap();
@@ -368,3 +1855,1194 @@ impl<'a> Parser<'a> {
             .to_owned()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use project;
+    use std;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    /// The number of fixtures created so far, used to give each of them its own directory.
+    static FIXTURES: AtomicUsize = AtomicUsize::new(0);
+
+    /// A project written to a temporary directory, which is removed when the fixture is dropped.
+    struct Fixture {
+        dir: std::path::PathBuf,
+    }
+
+    impl Fixture {
+        /// Writes a project's files, along with a `project.xml` building `main.xml` as the `main`
+        /// target if none is given.
+        fn new(files: &[(&str, &str)]) -> Fixture {
+            let dir = std::env::temp_dir().join(format!("bbcoder-test-{}-{}",
+                                                        std::process::id(),
+                                                        FIXTURES.fetch_add(1, Ordering::SeqCst)));
+            let mut files = files.to_vec();
+            if !files.iter().any(|&(name, _)| name == "project.xml") {
+                files.push(("project.xml",
+                            "<project><targets><target name=\"main\" src=\"main.xml\" />\
+                             </targets></project>"));
+            }
+            for (name, content) in files {
+                let path = dir.join(name);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, content).unwrap();
+            }
+            Fixture { dir: dir }
+        }
+
+        /// Loads the fixture's project.
+        fn load(&self) -> project::Project {
+            let mut project = project::Project::new();
+            project.load(&self.dir.join("project.xml")).unwrap();
+            project
+        }
+
+        /// Builds a target of the project, and returns its output.
+        fn build(&self, project: &project::Project, target: &str) -> Result<String, String> {
+            self.build_with_warnings(project, target).map(|(output, _)| output)
+        }
+
+        /// Builds a target of the project, and returns its output along with the warnings
+        /// reported, in alphabetical order and with paths relative to the fixture.
+        fn build_with_warnings(&self,
+                               project: &project::Project,
+                               target: &str)
+                               -> Result<(String, Vec<String>), String> {
+            let target_info = &project.targets[target];
+            let root_path = project.find_file(&target_info.src, &self.dir).unwrap();
+            let output_path = self.dir.join("target").join(format!("{}.txt", target));
+            let mut parser = Parser::new(project);
+            parser.output_bbcode(target_info, &root_path, target_info.body.as_deref(), &output_path)?;
+            let prefix = format!("{}/", self.dir.display());
+            let mut warnings: Vec<String> = parser
+                .warnings
+                .iter()
+                .map(|warning| warning.replace(&prefix, ""))
+                .collect();
+            warnings.sort();
+            Ok((self.read(&format!("target/{}.txt", target)), warnings))
+        }
+
+        /// Builds a target of the project which is split into posts, and returns them in order.
+        fn build_posts(&self, project: &project::Project, target: &str) -> Vec<String> {
+            let target_info = &project.targets[target];
+            let root_path = project.find_file(&target_info.src, &self.dir).unwrap();
+            let output_path = self.dir.join("target").join(format!("{}.txt", target));
+            Parser::new(project)
+                .output_bbcode(target_info, &root_path, None, &output_path)
+                .unwrap();
+            let mut posts = Vec::new();
+            while self.dir.join(format!("target/{}.{}.txt", target, posts.len() + 1)).exists() {
+                posts.push(self.read(&format!("target/{}.{}.txt", target, posts.len() + 1)));
+            }
+            posts
+        }
+
+        /// Reads a file of the fixture.
+        fn read(&self, name: &str) -> String {
+            std::fs::read_to_string(self.dir.join(name)).unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Builds a single BBXML file, and returns its output.
+    fn render(bbxml: &str) -> String {
+        let fixture = Fixture::new(&[("main.xml", bbxml)]);
+        fixture.build(&fixture.load(), "main").unwrap()
+    }
+
+    /// Builds a single BBXML file, and returns the warnings reported.
+    fn render_warnings(bbxml: &str) -> Vec<String> {
+        let fixture = Fixture::new(&[("main.xml", bbxml)]);
+        fixture.build_with_warnings(&fixture.load(), "main").unwrap().1
+    }
+
+    #[test]
+    fn for_iterates_over_repeated_params() {
+        assert_eq!(render(r#"<bbxml>
+                                 <templates>
+                                     <template name="list">
+                                         <for each="item" in="items">{index}.{item};</for>
+                                     </template>
+                                 </templates>
+                                 <body>
+                                     <include template="list">
+                                         <param name="items">a</param>
+                                         <param name="items"><b>b</b></param>
+                                     </include>
+                                 </body>
+                             </bbxml>"#),
+                   "1.a;2.[B]b[/B];");
+    }
+
+    #[test]
+    fn for_iterates_over_items() {
+        assert_eq!(render(r#"<bbxml>
+                                 <templates>
+                                     <template name="list">
+                                         <for each="x" in="xs" index="i">{i}:{x};</for>
+                                     </template>
+                                 </templates>
+                                 <body>
+                                     <include template="list">
+                                         <param name="xs"><item>a</item><item><i>b</i></item></param>
+                                     </include>
+                                 </body>
+                             </bbxml>"#),
+                   "1:a;2:[I]b[/I];");
+    }
+
+    #[test]
+    fn for_skips_unbound_lists() {
+        assert_eq!(render(r#"<bbxml>
+                                 <templates>
+                                     <template name="list">(<for each="x" in="xs">{x}</for>)</template>
+                                 </templates>
+                                 <body><include template="list" /></body>
+                             </bbxml>"#),
+                   "()");
+    }
+
+    #[test]
+    fn for_requires_its_attributes() {
+        let fixture = Fixture::new(&[("main.xml",
+                                      r#"<bbxml><body><for in="xs">x</for></body></bbxml>"#)]);
+        assert_eq!(fixture.build(&fixture.load(), "main"),
+                   Err("Missing 'each' attribute in for".to_owned()));
+    }
+
+    /// A project whose variables are each overridden by the next scope: project, target, file,
+    /// template and include parameters.
+    fn var_scopes(main: &str) -> Fixture {
+        Fixture::new(&[("project.xml",
+                        r#"<project>
+                               <vars>
+                                   <var name="a" value="project" />
+                                   <var name="b" value="project" />
+                                   <var name="c" value="project" />
+                                   <var name="d" value="project" />
+                               </vars>
+                               <targets>
+                                   <target name="main" src="main.xml">
+                                       <var name="b" value="target" />
+                                       <var name="c" value="target" />
+                                       <var name="d" value="target" />
+                                   </target>
+                               </targets>
+                           </project>"#),
+                       ("main.xml", main)])
+    }
+
+    #[test]
+    fn vars_are_overridden_by_narrower_scopes() {
+        let fixture = var_scopes(r#"<bbxml>
+                                        <vars><var name="c" value="file" /><var name="d" value="file" /></vars>
+                                        <body><vars><var name="d" value="body" /></vars>{a} {b} {c} {d}</body>
+                                    </bbxml>"#);
+        assert_eq!(fixture.build(&fixture.load(), "main").unwrap(), "project target file body");
+    }
+
+    #[test]
+    fn template_vars_are_defaults_for_params() {
+        let fixture = var_scopes(r#"<bbxml>
+                                        <vars><var name="d" value="file" /></vars>
+                                        <templates>
+                                            <template name="t">
+                                                <vars>
+                                                    <var name="c" value="template" />
+                                                    <var name="d"><b>template</b></var>
+                                                </vars>
+                                                {b} {c} {d}
+                                            </template>
+                                        </templates>
+                                        <body><include template="t"><param name="c">param</param></include></body>
+                                    </bbxml>"#);
+        assert_eq!(fixture.build(&fixture.load(), "main").unwrap(),
+                   "target param [B]template[/B]");
+    }
+
+    #[test]
+    fn vars_are_substituted_in_options_and_classes() {
+        assert_eq!(render(r#"<bbxml>
+                                 <vars><var name="accent" value="green" /></vars>
+                                 <classes><class name="c">color: {accent}</class></classes>
+                                 <body><color option="{accent}">a</color><div class="c">b</div></body>
+                             </bbxml>"#),
+                   "[COLOR=green]a[/COLOR][DIV=color: green;]b[/DIV]");
+    }
+
+    #[test]
+    fn defines_override_every_var_scope() {
+        let fixture = var_scopes(r#"<bbxml>
+                                        <vars><var name="c" value="file" /></vars>
+                                        <templates>
+                                            <template name="t">
+                                                <vars><var name="d" value="template" /></vars>
+                                                {a} {b} {c} {d} {e}
+                                            </template>
+                                        </templates>
+                                        <body>
+                                            <vars><var name="c" value="body" /></vars>
+                                            <include template="t"><param name="e">param</param></include>
+                                        </body>
+                                    </bbxml>"#);
+        let mut project = fixture.load();
+        for name in &["a", "b", "c", "d", "e"] {
+            let (name, value) = project::parse_define(&format!("{}=x", name)).unwrap();
+            project.defines.insert(name, value);
+        }
+        assert_eq!(fixture.build(&project, "main").unwrap(), "x x x x param");
+    }
+
+    #[test]
+    fn defines_must_have_a_name_and_value() {
+        assert_eq!(project::parse_define("a=b=c").unwrap().1.text(), "b=c");
+        assert!(project::parse_define("=x").is_err());
+        assert!(project::parse_define("x").is_err());
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(render(r#"<bbxml>
+                                 <templates><template name="t">{{x}} {x} {{{x}}}</template></templates>
+                                 <body><include template="t"><param name="x">y</param></include></body>
+                             </bbxml>"#),
+                   "{x} y {y}");
+    }
+
+    #[test]
+    fn escape_neutralizes_brackets_in_text_only() {
+        assert_eq!(render(r#"<bbxml>
+                                 <templates><template name="t"><i escape="true">[b]{x}</i></template></templates>
+                                 <body><include template="t"><param name="x"><b>y</b></param></include></body>
+                             </bbxml>"#),
+                   "[I][\u{200B}b][B]y[/B][/I]");
+    }
+
+    /// Builds a single BBXML file for a target of the specified dialect, and returns its output.
+    fn render_dialect(dialect: &str, bbxml: &str) -> String {
+        let project = format!(r#"<project>
+                                     <targets>
+                                         <target name="main" src="main.xml" dialect="{}" />
+                                     </targets>
+                                 </project>"#,
+                              dialect);
+        let fixture = Fixture::new(&[("project.xml", &project), ("main.xml", bbxml)]);
+        fixture.build(&fixture.load(), "main").unwrap()
+    }
+
+    #[test]
+    fn text_is_escaped_per_dialect() {
+        let bbxml = r#"<bbxml><body><b>[i]</b> <b raw="true">[i]</b></body></bbxml>"#;
+        assert_eq!(render_dialect("generic", bbxml), "[B][\u{200B}i][/B] [B][i][/B]");
+        assert_eq!(render_dialect("rpnation", bbxml), "[B]&#91;i][/B] [B][i][/B]");
+        assert_eq!(render_dialect("vbulletin", bbxml), "[B][NOPARSE][i][/NOPARSE][/B] [B][i][/B]");
+    }
+
+    #[test]
+    fn escape_attribute_overrides_the_dialect() {
+        assert_eq!(render_dialect("generic",
+                                  r#"<bbxml>
+                                         <body>
+                                             <i escape="plain">[a]</i><i escape="false">[b]</i>
+                                             <i plain="true">[c]</i><i plain="true" escape="true">[d]</i>
+                                         </body>
+                                     </bbxml>"#),
+                   "[I][PLAIN][a][/PLAIN][/I][I][b][/I][I][c][/I][I][\u{200B}d][/I]");
+    }
+
+    #[test]
+    fn wrappers_cannot_be_closed_by_text() {
+        assert_eq!(render_dialect("vbulletin", "<bbxml><body>[/NoParse][b]</body></bbxml>"),
+                   "[NOPARSE][\u{200B}/NoParse][b][/NOPARSE]");
+    }
+
+    #[test]
+    fn adjacent_escaped_text_shares_a_wrapper() {
+        assert_eq!(render_dialect("vbulletin",
+                                  r#"<bbxml>
+                                         <templates><template name="t"><b>[{name}] says [hi]</b></template></templates>
+                                         <body><include template="t"><param name="name">[Joe]</param></include></body>
+                                     </bbxml>"#),
+                   "[B][NOPARSE][[Joe]] says [hi][/NOPARSE][/B]");
+    }
+
+    #[test]
+    fn classes_inherit_their_parents_in_order() {
+        assert_eq!(render(r#"<bbxml>
+                                 <classes>
+                                     <class name="a">color: red;</class>
+                                     <class name="b" extends="a">width: 1px;</class>
+                                     <class name="c">height: 2px;</class>
+                                     <class name="d" extends="b c">float: left;</class>
+                                 </classes>
+                                 <body><div class="d">x</div></body>
+                             </bbxml>"#),
+                   "[DIV=color: red; width: 1px; height: 2px; float: left;]x[/DIV]");
+    }
+
+    #[test]
+    fn class_inheritance_cycles_are_errors() {
+        let fixture = Fixture::new(&[("main.xml",
+                                      r#"<bbxml>
+                                             <classes>
+                                                 <class name="a" extends="c">color: red;</class>
+                                                 <class name="b" extends="a">width: 1px;</class>
+                                                 <class name="c" extends="b">height: 2px;</class>
+                                             </classes>
+                                             <body><div class="a">x</div></body>
+                                         </bbxml>"#)]);
+        let error = fixture.build(&fixture.load(), "main").unwrap_err();
+        assert!(error.starts_with("Circular class inheritance: "), "{}", error);
+    }
+
+    #[test]
+    fn classes_cannot_extend_unknown_classes() {
+        let fixture = Fixture::new(&[("main.xml",
+                                      r#"<bbxml>
+                                             <classes><class name="a" extends="b">color: red;</class></classes>
+                                             <body><div class="a">x</div></body>
+                                         </bbxml>"#)]);
+        assert_eq!(fixture.build(&fixture.load(), "main"),
+                   Err("Class 'a' extends unknown class 'b'".to_owned()));
+    }
+
+    #[test]
+    fn classes_and_options_are_merged_as_styles() {
+        assert_eq!(render(r#"<bbxml>
+                                 <classes>
+                                     <class name="a">color: red; width: 1px;</class>
+                                     <class name="b" extends="a">width: 2px; height: 1px !important;</class>
+                                 </classes>
+                                 <body><div class="b" option="height: 5px; color: blue">x</div></body>
+                             </bbxml>"#),
+                   "[DIV=color: blue; width: 2px; height: 1px !important;]x[/DIV]");
+    }
+
+    #[test]
+    fn options_without_classes_are_kept_as_they_are() {
+        assert_eq!(render(r#"<bbxml><body><div option=" width:500px ">x</div></body></bbxml>"#),
+                   "[DIV=width:500px]x[/DIV]");
+    }
+
+    #[test]
+    fn class_placeholders_can_stand_for_declarations() {
+        assert_eq!(render(r#"<bbxml>
+                                 <classes><class name="c">color: red; {extra}</class></classes>
+                                 <templates><template name="t"><div class="c">x</div></template></templates>
+                                 <body>
+                                     <include template="t"><param name="extra">width: 1px; color: blue</param></include>
+                                 </body>
+                             </bbxml>"#),
+                   "[DIV=color: blue; width: 1px;]x[/DIV]");
+    }
+
+    #[test]
+    fn class_arguments_override_params_within_the_class_only() {
+        assert_eq!(render(r#"<bbxml>
+                                 <vars><var name="color" value="green" /></vars>
+                                 <classes><class name="badge">color: {color}; width: {width}</class></classes>
+                                 <templates>
+                                     <template name="t">
+                                         <div class="badge(color=red, width=calc(50% - 2px))">{color}</div>
+                                         <div class="badge">{color}</div>
+                                     </template>
+                                 </templates>
+                                 <body><include template="t"><param name="width">1px</param></include></body>
+                             </bbxml>"#),
+                   "[DIV=color: red; width: calc(50% - 2px);]green[/DIV]\
+                    [DIV=color: green; width: 1px;]green[/DIV]");
+    }
+
+    #[test]
+    fn malformed_class_arguments_are_errors() {
+        for &(classes, error) in &[("badge(color=red", "Unterminated arguments in class 'badge(color=red'"),
+                                   ("badge(red)", "Invalid argument 'red' in class 'badge', expected 'name=value'")] {
+            let fixture = Fixture::new(&[("main.xml",
+                                          &format!(r#"<bbxml>
+                                                          <classes><class name="badge">color: {{color}}</class></classes>
+                                                          <body><div class="{}">x</div></body>
+                                                      </bbxml>"#,
+                                                   classes))]);
+            assert_eq!(fixture.build(&fixture.load(), "main"), Err(error.to_owned()));
+        }
+    }
+
+    #[test]
+    fn div_options_are_kept_unless_minified() {
+        let bbxml = r#"<bbxml>
+                           <classes><class name="c">color: red; font-family: "A, B", serif</class></classes>
+                           <body><div option="width:500px">x</div><div class="c" option="width: 5px">y</div></body>
+                       </bbxml>"#;
+        assert_eq!(render(bbxml),
+                   "[DIV=width:500px]x[/DIV]\
+                    [DIV=color: red; font-family: \"A, B\", serif; width: 5px;]y[/DIV]");
+        let fixture = Fixture::new(&[("project.xml",
+                                      r#"<project>
+                                             <targets minify="true"><target name="main" src="main.xml" /></targets>
+                                         </project>"#),
+                                     ("main.xml", bbxml)]);
+        assert_eq!(fixture.build(&fixture.load(), "main").unwrap(),
+                   "[DIV=width:500px]x[/DIV][DIV=color:red;font-family:\"A, B\",serif;width:5px]y[/DIV]");
+    }
+
+    #[test]
+    fn style_problems_are_warnings() {
+        assert_eq!(render_warnings(r#"<bbxml>
+                                          <classes><class name="c">colour: red; {extra}</class></classes>
+                                          <templates><template name="t"><div class="c" o="width 5px">x</div></template></templates>
+                                          <body><include template="t"><param name="extra">height</param></include></body>
+                                      </bbxml>"#),
+                   vec!["'main.xml', template 't'/div[1]: Option of 'div': Missing ':' in \
+                         declaration 'width 5px'",
+                        "Class 'c': Missing ':' in declaration 'height'",
+                        "Class 'c': Unknown property 'colour'"]);
+    }
+
+    #[test]
+    fn unknown_classes_are_located_and_suggested() {
+        let fixture = Fixture::new(&[("main.xml",
+                                      r#"<bbxml>
+                                             <classes><class name="box">color: red</class></classes>
+                                             <templates>
+                                                 <template name="t"><b>x</b><b><i class="bxo">y</i></b></template>
+                                             </templates>
+                                             <body><div class="box"><include template="t" /></div></body>
+                                         </bbxml>"#)]);
+        let (output, warnings) = fixture.build_with_warnings(&fixture.load(), "main").unwrap();
+        assert_eq!(output, "[DIV=color: red;][B]x[/B][B][I]y[/I][/B][/DIV]");
+        assert_eq!(warnings,
+                   vec!["'main.xml', template 't'/b[2]/i[1]: Unknown class 'bxo' \
+                         (did you mean 'box'?)"]);
+    }
+
+    #[test]
+    fn strict_mode_only_rejects_unknown_classes() {
+        let fixture = Fixture::new(&[("main.xml",
+                                      r#"<bbxml>
+                                             <classes>
+                                                 <class name="a">colour: red</class>
+                                                 <class name="unused" extends="missing">color: red</class>
+                                             </classes>
+                                             <body><b class="a">x</b><i class="{c}">y</i></body>
+                                         </bbxml>"#)]);
+        let mut project = fixture.load();
+        project.strict = true;
+        let error = fixture.build(&project, "main").unwrap_err();
+        assert!(error.ends_with("main.xml', body/i[1]: Unknown class '{c}'"), "{}", error);
+
+        let (_, warnings) = fixture.build_with_warnings(&fixture.load(), "main").unwrap();
+        assert_eq!(warnings,
+                   vec!["'main.xml', body/i[1]: Unknown class '{c}'",
+                        "Class 'a': Unknown property 'colour'"]);
+    }
+
+    #[test]
+    fn edit_distance_counts_transpositions() {
+        assert_eq!(Parser::edit_distance("", "box"), 3);
+        assert_eq!(Parser::edit_distance("box", "box"), 0);
+        assert_eq!(Parser::edit_distance("bx", "box"), 1);
+        assert_eq!(Parser::edit_distance("bxo", "box"), 1);
+        assert_eq!(Parser::edit_distance("prety-box", "pretty-box"), 1);
+        assert_eq!(Parser::edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn suggest_finds_the_closest_name() {
+        let names: Vec<String> = vec!["box".to_owned(), "pretty-box".to_owned(), "red".to_owned()];
+        assert_eq!(Parser::suggest("bxo", names.iter()), " (did you mean 'box'?)");
+        assert_eq!(Parser::suggest("bo", names.iter()), " (did you mean 'box'?)");
+        assert_eq!(Parser::suggest("prety-box", names.iter()), " (did you mean 'pretty-box'?)");
+        assert_eq!(Parser::suggest("pretty-bxo", names.iter()), " (did you mean 'pretty-box'?)");
+        assert_eq!(Parser::suggest("yellow", names.iter()), "");
+    }
+
+    #[test]
+    fn redefinitions_are_warnings_unless_overridden() {
+        let fixture = Fixture::new(&[("lib.xml",
+                                      r#"<bbxml>
+                                             <include src="main.xml" />
+                                             <classes><class name="a">color: red</class><class name="b">color: red</class></classes>
+                                             <templates><template name="t">lib</template></templates>
+                                         </bbxml>"#),
+                                     ("main.xml",
+                                      r#"<bbxml>
+                                             <include src="lib.xml" />
+                                             <classes>
+                                                 <class name="a">color: blue</class>
+                                                 <class name="b" override="true">color: blue</class>
+                                             </classes>
+                                             <templates><template name="t">main</template></templates>
+                                             <body><include template="t" /><b class="a">x</b><b class="b">y</b></body>
+                                         </bbxml>"#)]);
+        let (output, warnings) = fixture.build_with_warnings(&fixture.load(), "main").unwrap();
+        assert_eq!(output, "main[B=color: blue;]x[/B][B=color: blue;]y[/B]");
+        assert_eq!(warnings,
+                   vec!["'main.xml': Class 'a' replaces its definition from 'lib.xml' (add \
+                         override=\"true\" if this is intended)",
+                        "'main.xml': Template 't' replaces its definition from 'lib.xml' (add \
+                         override=\"true\" if this is intended)"]);
+    }
+
+    /// A project including two libraries, in the `ui` and `alt` namespaces, which define the same
+    /// names as the file including them.
+    fn namespaces(main_body: &str) -> Fixture {
+        let lib = r#"<bbxml>
+                         <vars><var name="accent" value="{library}" /></vars>
+                         <classes><class name="box">color: {accent}</class></classes>
+                         <templates>
+                             <template name="panel"><div class="box">{accent}:{content}</div></template>
+                         </templates>
+                     </bbxml>"#;
+        let main = format!(r#"<bbxml>
+                                  <include src="ui.xml" as="ui" />
+                                  <include src="alt.xml" as="alt" />
+                                  <vars><var name="accent" value="main" /></vars>
+                                  <classes><class name="box">color: blue</class></classes>
+                                  <templates><template name="panel">main panel</template></templates>
+                                  <body>{}</body>
+                              </bbxml>"#,
+                           main_body);
+        Fixture::new(&[("ui.xml", &lib.replace("{library}", "red")),
+                       ("alt.xml", &lib.replace("{library}", "green")),
+                       ("main.xml", &main)])
+    }
+
+    #[test]
+    fn namespaced_definitions_are_prefixed() {
+        let fixture = namespaces(r#"<include template="panel" /><b class="ui:box">x</b>
+                                    <include template="alt:panel"><param name="content">y</param></include>"#);
+        assert_eq!(fixture.build(&fixture.load(), "main").unwrap(),
+                   "main panel[B=color: red;]x[/B][DIV=color: green;]green:y[/DIV]");
+    }
+
+    #[test]
+    fn params_are_parsed_in_the_callers_namespace() {
+        let fixture = namespaces(r#"<include template="ui:panel">
+                                        <param name="content"><span class="box">x</span><include template="panel" /></param>
+                                    </include>"#);
+        assert_eq!(fixture.build(&fixture.load(), "main").unwrap(),
+                   "[DIV=color: red;]red:[SPAN=color: blue;]x[/SPAN]main panel[/DIV]");
+    }
+
+    #[test]
+    fn namespaced_vars_are_prefixed() {
+        let fixture = namespaces("{accent} {ui:accent} {alt:accent}");
+        assert_eq!(fixture.build(&fixture.load(), "main").unwrap(), "main red green");
+
+        let fixture = namespaces(r#"<include template="ui:panel" />"#);
+        let mut project = fixture.load();
+        let (name, value) = project::parse_define("accent=black").unwrap();
+        project.defines.insert(name, value);
+        assert_eq!(fixture.build(&project, "main").unwrap(), "[DIV=color: black;]black:{content}[/DIV]");
+    }
+
+    #[test]
+    fn params_are_replaced_in_class_template_and_tag_names() {
+        assert_eq!(render(r#"<bbxml>
+                                 <classes><class name="red">color: red</class></classes>
+                                 <templates>
+                                     <template name="t"><tag name="{tag}" c="{class}"><in t="{next}" /></tag></template>
+                                     <template name="inner">inner</template>
+                                 </templates>
+                                 <body>
+                                     <include template="t">
+                                         <param name="tag">h1</param>
+                                         <param name="class">red</param>
+                                         <param name="next">inner</param>
+                                     </include>
+                                 </body>
+                             </bbxml>"#),
+                   "[H1=color: red;]inner[/H1]");
+    }
+
+    #[test]
+    fn tag_names_must_be_valid_and_not_reserved() {
+        assert_eq!(render(r#"<bbxml><body><tag name="*">x</tag></body></bbxml>"#), "[*]x[/*]");
+        for &(name, error) in &[("", "Invalid tag name ''"),
+                                ("h-1", "Invalid tag name 'h-1'"),
+                                ("1h", "Invalid tag name '1h'"),
+                                ("include", "Reserved tag name 'include'"),
+                                ("tag", "Reserved tag name 'tag'")] {
+            let fixture = Fixture::new(&[("main.xml",
+                                          r#"<bbxml><body><tag name="{name}">x</tag></body></bbxml>"#)]);
+            let mut project = fixture.load();
+            let (define, value) = project::parse_define(&format!("name={}", name)).unwrap();
+            project.defines.insert(define, value);
+            let prefix = format!("'{}', body/tag[1]: ", fixture.dir.join("main.xml").display());
+            assert_eq!(fixture.build(&project, "main"), Err(format!("{}{}", prefix, error)));
+        }
+    }
+
+    #[test]
+    fn templates_override_the_blocks_of_their_base() {
+        assert_eq!(render(r#"<bbxml>
+                                 <templates>
+                                     <template name="base">
+                                         <vars><var name="who" value="base" /></vars>
+                                         <b><block name="title">Untitled</block></b>
+                                         <block name="content"><block name="intro">Hi</block> {who}</block>.
+                                     </template>
+                                     <template name="middle" extends="base">
+                                         <block name="intro">Hello</block>
+                                         ignored
+                                     </template>
+                                     <template name="post" extends="middle">
+                                         <vars><var name="who" value="post" /></vars>
+                                         <i><block name="title">{title}</block></i>
+                                     </template>
+                                 </templates>
+                                 <body><include template="post"><param name="title">T</param></include></body>
+                             </bbxml>"#),
+                   "[B]T[/B]Hello post.");
+    }
+
+    #[test]
+    fn bodies_can_extend_templates() {
+        assert_eq!(render(r#"<bbxml>
+                                 <templates>
+                                     <template name="base">
+                                         <vars><var name="who" value="base" /><var name="what" value="base" /></vars>
+                                         <block name="content">{who}</block> {what}
+                                     </template>
+                                 </templates>
+                                 <body extends="base">
+                                     <vars><var name="what" value="body" /></vars>
+                                     <block name="content">[{who}]</block>
+                                 </body>
+                             </bbxml>"#),
+                   "[\u{200B}base] body");
+    }
+
+    #[test]
+    fn blocks_missing_from_the_base_are_warnings() {
+        assert_eq!(render_warnings(r#"<bbxml>
+                                          <templates>
+                                              <template name="base"><block name="a" /></template>
+                                              <template name="t" extends="base"><block name="b" /></template>
+                                          </templates>
+                                          <body extends="base"><block name="a" /><div><block name="c" /></div></body>
+                                      </bbxml>"#),
+                   vec!["'main.xml', body: Block 'c' is not defined in the template it extends",
+                        "'main.xml', template 't': Block 'b' is not defined in the template it extends"]);
+    }
+
+    #[test]
+    fn template_inheritance_errors() {
+        for &(main, error) in &[(r#"<templates><template name="base" /></templates><body extends="bsae" />"#,
+                                 "body: Body extends unknown template 'bsae' (did you mean 'base'?)"),
+                                (r#"<templates>
+                                        <template name="a" extends="b" />
+                                        <template name="b" extends="a" />
+                                    </templates>
+                                    <body />"#,
+                                 "Circular template inheritance: "),
+                                (r#"<templates><template name="c" extends="d" /></templates><body />"#,
+                                 "main.xml': Template 'c' extends unknown template 'd'")] {
+            let fixture = Fixture::new(&[("main.xml", &format!("<bbxml>{}</bbxml>", main))]);
+            let result = fixture.build(&fixture.load(), "main");
+            assert!(result.as_ref().unwrap_err().contains(error), "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn namespaced_templates_can_be_extended() {
+        let lib = r#"<bbxml>
+                         <vars><var name="accent" value="red" /></vars>
+                         <classes><class name="box">color: {accent}</class></classes>
+                         <templates>
+                             <template name="base"><div class="box"><block name="content" /></div></template>
+                         </templates>
+                     </bbxml>"#;
+        let main = r#"<bbxml>
+                          <include src="ui.xml" as="ui" />
+                          <classes><class name="box">color: blue</class></classes>
+                          <templates>
+                              <template name="post" extends="ui:base">
+                                  <block name="content"><b class="box">{accent}</b></block>
+                              </template>
+                          </templates>
+                          <body extends="ui:base"><block name="content"><include template="post" /></block></body>
+                      </bbxml>"#;
+        let fixture = Fixture::new(&[("ui.xml", lib), ("main.xml", main)]);
+        assert_eq!(fixture.build(&fixture.load(), "main").unwrap(),
+                   "[DIV=color: red;][DIV=color: red;][B=color: blue;]red[/B][/DIV][/DIV]");
+    }
+
+    #[test]
+    fn let_binds_its_rendered_content_for_the_following_siblings() {
+        assert_eq!(render(r#"<bbxml>
+                                 <templates><template name="t">({styled})</template></templates>
+                                 <body>
+                                     {styled}
+                                     <let name="who">Joe</let>
+                                     <let name="styled"><b>{who}</b> [x] {{y}}</let>
+                                     <let name="who">Ann</let>
+                                     <i>{styled}</i>
+                                     <include template="t" />
+                                     {who}
+                                 </body>
+                             </bbxml>"#),
+                   "{styled}[I][B]Joe[/B] [\u{200B}x] {y}[/I]([B]Joe[/B] [\u{200B}x] {y})Ann");
+    }
+
+    #[test]
+    fn let_bindings_are_local_to_their_parent() {
+        assert_eq!(render(r#"<bbxml><body><i><let name="x">inner</let>{x}</i>{x}</body></bbxml>"#),
+                   "[I]inner[/I]{x}");
+    }
+
+    fn scoping(mode: &str) -> Fixture {
+        Fixture::new(&[("project.xml",
+                        &format!(r#"<project scoping="{}">
+                                        <targets><target name="main" src="main.xml" /></targets>
+                                    </project>"#,
+                                 mode)),
+                       ("main.xml",
+                        r#"<bbxml>
+                               <vars><var name="name" value="global" /><var name="same" value="global" /></vars>
+                               <templates>
+                                   <template name="inner">{name} {missing} {same} {passed} {extra}</template>
+                                   <template name="outer">
+                                       <include template="inner" pass="passed"><param name="extra">{name}</param></include>
+                                   </template>
+                               </templates>
+                               <body>
+                                   <include template="outer">
+                                       <param name="name">caller</param>
+                                       <param name="missing">caller</param>
+                                       <param name="passed">caller</param>
+                                   </include>
+                               </body>
+                           </bbxml>"#)])
+    }
+
+    #[test]
+    fn dynamic_scoping_passes_every_param_down() {
+        let fixture = scoping("dynamic");
+        assert_eq!(fixture.build_with_warnings(&fixture.load(), "main").unwrap(),
+                   ("caller caller global caller caller".to_owned(), vec![]));
+    }
+
+    #[test]
+    fn lexical_scoping_warns_where_dynamic_scoping_differs() {
+        let fixture = scoping("lexical");
+        assert_eq!(fixture.build_with_warnings(&fixture.load(), "main").unwrap(),
+                   ("global {missing} global caller caller".to_owned(),
+                    vec!["'main.xml', template 'inner': 'missing' is not passed to this template, but \
+                          the caller's would have been used with dynamic scoping".to_owned(),
+                         "'main.xml', template 'inner': 'name' refers to a global variable, but the \
+                          caller's 'name' would have been used with dynamic scoping".to_owned()]));
+    }
+
+    #[test]
+    fn scoping_mode_must_be_known() {
+        let fixture = scoping("static");
+        let mut project = project::Project::new();
+        assert_eq!(project.load(&fixture.dir.join("project.xml")),
+                   Err("Unknown scoping mode 'static'".to_owned()));
+    }
+
+    fn whitespace(mode: &str, main: &str) -> String {
+        let fixture = Fixture::new(&[("project.xml",
+                                      &format!(r#"<project>
+                                                      <targets whitespace="{}"><target name="main" src="main.xml" /></targets>
+                                                  </project>"#,
+                                               mode)),
+                                     ("main.xml", main)]);
+        fixture.build(&fixture.load(), "main").unwrap()
+    }
+
+    #[test]
+    fn collapsed_whitespace_separates_inline_content() {
+        let main = r#"<bbxml>
+                          <templates><template name="t"> <u>{x}</u> </template></templates>
+                          <body>
+                              <b>Name</b>
+                              <i>Joe</i> wrote
+                              this <include template="t"><param name="x">  long   text </param></include>.
+                          </body>
+                      </bbxml>"#;
+        assert_eq!(whitespace("compact", main), "[B]Name[/B][I]Joe[/I] wrotethis  [U]  long   text [/U] .");
+        assert_eq!(whitespace("collapse", main), "[B]Name[/B] [I]Joe[/I] wrote this [U]long text[/U] .");
+    }
+
+    #[test]
+    fn collapsed_whitespace_is_trimmed_at_block_boundaries() {
+        assert_eq!(whitespace("collapse",
+                              r#"<bbxml>
+                                     <body>
+                                         Before
+                                         <quote> Quoted <b>text</b> </quote>
+                                         <list>
+                                             <li> One </li>
+                                             <li>Two <br /> lines</li>
+                                         </list>
+                                         After <i>this</i>
+                                     </body>
+                                 </bbxml>"#),
+                   "Before[QUOTE]Quoted [B]text[/B][/QUOTE][LIST][*]One[*]Two\nlines[/LIST]After [I]this[/I]");
+    }
+
+    #[test]
+    fn plain_elements_keep_their_whitespace() {
+        assert_eq!(whitespace("collapse",
+                              "<bbxml><body>a <code plain=\"true\">  x\n  y </code> b</body></bbxml>"),
+                   "a[CODE]  x\n  y [/CODE]b");
+    }
+
+    #[test]
+    fn whitespace_mode_must_be_known() {
+        let fixture = Fixture::new(&[("project.xml",
+                                      r#"<project><targets whitespace="html"><target name="main" src="main.xml" /></targets></project>"#)]);
+        let mut project = project::Project::new();
+        assert_eq!(project.load(&fixture.dir.join("project.xml")),
+                   Err("Unknown whitespace mode 'html'".to_owned()));
+    }
+
+    #[test]
+    fn whitespace_modes_apply_to_descendants_and_included_templates() {
+        assert_eq!(render(r#"<bbxml>
+                                 <templates>
+                                     <template name="t"><u>in
+                                         template</u></template>
+                                 </templates>
+                                 <body>
+                                     <b whitespace="trim">  a  <i>  b  </i>  </b>
+                                     <s whitespace="preserve-lines">
+                                         one  <include template="t" />
+                                         two
+                                     </s>
+                                     <code whitespace="preserve"> x </code>
+                                     <i>
+                                         back</i>
+                                 </body>
+                             </bbxml>"#),
+                   "[B]a[I]b[/I][/B][S]\none  [U]in\ntemplate[/U]\ntwo\n[/S][CODE] x [/CODE][I]back[/I]");
+    }
+
+    #[test]
+    fn file_whitespace_modes_apply_to_its_body_and_templates() {
+        let fixture = Fixture::new(&[("lib.xml",
+                                      r#"<bbxml whitespace="collapse">
+                                             <templates><template name="lib">a
+                                                 b</template></templates>
+                                         </bbxml>"#),
+                                     ("main.xml",
+                                      r#"<bbxml whitespace="preserve-lines">
+                                             <include src="lib.xml" />
+                                             <templates>
+                                                 <template name="main">c
+                                                     d</template>
+                                                 <template name="own" whitespace="compact">e
+                                                     f</template>
+                                             </templates>
+                                             <body><include template="lib" /> <include template="main" /> <include template="own" /></body>
+                                         </bbxml>"#)]);
+        assert_eq!(fixture.build(&fixture.load(), "main").unwrap(), "a b c\nd ef");
+    }
+
+    #[test]
+    fn plain_is_not_inherited() {
+        assert_eq!(render("<bbxml><body><b plain=\"true\">a\n  <i>b\n  c</i>\n</b></body></bbxml>"),
+                   "[B]a\n  [I]bc[/I]\n[/B]");
+    }
+
+    #[test]
+    fn whitespace_modes_must_be_known() {
+        let fixture = Fixture::new(&[("main.xml", r#"<bbxml><body><b whitespace="none">x</b></body></bbxml>"#)]);
+        assert_eq!(fixture.build(&fixture.load(), "main"),
+                   Err("Unknown whitespace mode 'none'".to_owned()));
+    }
+
+    #[test]
+    fn targets_choose_line_endings_and_trailing_newlines() {
+        let fixture = Fixture::new(&[("project.xml",
+                                      r#"<project>
+                                             <targets line-endings="crlf" trailing-newline="always">
+                                                 <target name="main" src="main.xml" />
+                                                 <target name="lf" src="main.xml" line-endings="lf" trailing-newline="never" />
+                                             </targets>
+                                         </project>"#),
+                                     ("main.xml", "<bbxml><body>a<br />b<br /><br /></body></bbxml>")]);
+        let project = fixture.load();
+        assert_eq!(fixture.build(&project, "main").unwrap(), "a\r\nb\r\n");
+        assert_eq!(fixture.build(&project, "lf").unwrap(), "a\nb");
+    }
+
+    #[test]
+    fn line_endings_and_trailing_newlines_must_be_known() {
+        for &(attr, error) in &[("line-endings=\"cr\"", "Unknown line endings 'cr'"),
+                                ("trailing-newline=\"maybe\"", "Unknown trailing newline behavior 'maybe'")] {
+            let fixture = Fixture::new(&[("project.xml",
+                                          &format!(r#"<project><targets {}><target name="main" src="main.xml" /></targets></project>"#,
+                                                   attr))]);
+            let mut project = project::Project::new();
+            assert_eq!(project.load(&fixture.dir.join("project.xml")), Err(error.to_owned()));
+        }
+    }
+
+    #[test]
+    fn outputs_over_the_maximum_length_are_reported() {
+        let fixture = Fixture::new(&[("project.xml",
+                                      r#"<project><targets max-length="10"><target name="main" src="main.xml" /></targets></project>"#),
+                                     ("main.xml", "<bbxml><body>aaaa bbbb cccc</body></bbxml>")]);
+        assert_eq!(fixture.build_with_warnings(&fixture.load(), "main").unwrap(),
+                   ("aaaa bbbb cccc".to_owned(),
+                    vec!["'target/main.txt' is 14 characters long, over the maximum of 10".to_owned()]));
+    }
+
+    #[test]
+    fn maximum_lengths_must_be_valid() {
+        for max_length in &["0", "-1", "long"] {
+            let fixture = Fixture::new(&[("project.xml",
+                                          &format!(r#"<project><targets max-length="{}"><target name="main" src="main.xml" /></targets></project>"#,
+                                                   max_length))]);
+            let mut project = project::Project::new();
+            assert_eq!(project.load(&fixture.dir.join("project.xml")),
+                       Err(format!("Invalid maximum length '{}'", max_length)));
+        }
+    }
+
+    #[test]
+    fn split_posts_include_line_endings_and_trailing_newlines() {
+        let fixture = Fixture::new(&[("project.xml",
+                                      r#"<project>
+                                             <targets max-length="15" split="true" line-endings="crlf" trailing-newline="always">
+                                                 <target name="main" src="main.xml" />
+                                             </targets>
+                                         </project>"#),
+                                     ("main.xml",
+                                      "<bbxml><body><b>aaaa bbbb</b><br />cc dd<br />eeee ffff gggg</body></bbxml>")]);
+        let posts = fixture.build_posts(&fixture.load(), "main");
+        assert_eq!(posts,
+                   vec!["[B]aaaa [/B]\r\n",
+                        "[B]bbbb[/B]\r\n",
+                        "cc dd\r\n",
+                        "eeee ffff \r\n",
+                        "gggg\r\n"]);
+        for post in &posts {
+            assert!(posts::length(post) <= 15, "'{}' is too long", post);
+        }
+        assert!(!fixture.dir.join("target/main.txt").exists());
+    }
+
+    #[test]
+    fn stale_posts_are_removed() {
+        let fixture = Fixture::new(&[("project.xml",
+                                      r#"<project><targets max-length="5" split="true"><target name="main" src="main.xml" /></targets></project>"#),
+                                     ("main.xml", "<bbxml><body>aaaa bbbb cccc</body></bbxml>")]);
+        let project = fixture.load();
+        assert_eq!(fixture.build_posts(&project, "main"), vec!["aaaa ", "bbbb ", "cccc"]);
+
+        // Fewer posts leave no part of the previous build behind
+        std::fs::write(fixture.dir.join("main.xml"), "<bbxml><body>aaaa bbbb</body></bbxml>")
+            .unwrap();
+        assert_eq!(fixture.build_posts(&project, "main"), vec!["aaaa ", "bbbb"]);
+        assert!(!fixture.dir.join("target/main.3.txt").exists());
+
+        // Neither does an output which fits in a single post
+        std::fs::write(fixture.dir.join("main.xml"), "<bbxml><body>aaaa</body></bbxml>").unwrap();
+        assert_eq!(fixture.build(&project, "main").unwrap(), "aaaa");
+        assert!(fixture.build_posts(&project, "main").is_empty());
+    }
+
+    #[test]
+    fn each_body_has_its_own_output() {
+        let fixture = Fixture::new(&[("main.xml",
+                                      r#"<bbxml>
+                                             <vars><var name="thread" value="Thread" /></vars>
+                                             <body>{thread}: opening</body>
+                                             <body name="reserved"><vars><var name="post" value="reserved" /></vars>{thread}: {post}</body>
+                                             <body name="rules">{thread}: <b class="missing">rules</b></body>
+                                         </bbxml>"#)]);
+        let (output, warnings) = fixture.build_with_warnings(&fixture.load(), "main").unwrap();
+        assert_eq!(output, "Thread: opening");
+        assert_eq!(fixture.read("target/main.reserved.txt"), "Thread: reserved");
+        assert_eq!(fixture.read("target/main.rules.txt"), "Thread: [B]rules[/B]");
+        assert_eq!(warnings,
+                   vec!["'main.xml', body 'rules'/b[1]: Unknown class 'missing'".to_owned()]);
+    }
+
+    #[test]
+    fn a_single_body_can_be_built() {
+        let fixture = Fixture::new(&[("project.xml",
+                                      r#"<project>
+                                             <targets>
+                                                 <target name="main" src="main.xml" />
+                                                 <target name="reserved" src="main.xml#reserved" />
+                                             </targets>
+                                         </project>"#),
+                                     ("main.xml",
+                                      r#"<bbxml><body>opening</body><body name="reserved">reserved</body></bbxml>"#)]);
+        let project = fixture.load();
+        assert_eq!(fixture.build(&project, "reserved").unwrap(), "reserved");
+        assert!(!fixture.dir.join("target/reserved.reserved.txt").exists());
+
+        let root_path = fixture.dir.join("main.xml");
+        let output_path = fixture.dir.join("target/main.reserved.txt");
+        Parser::new(&project)
+            .output_bbcode(&project.targets["main"], &root_path, Some("reserved"), &output_path)
+            .unwrap();
+        assert_eq!(fixture.read("target/main.reserved.txt"), "reserved");
+        assert!(!fixture.dir.join("target/main.txt").exists());
+        assert_eq!(Parser::new(&project).output_bbcode(&project.targets["main"],
+                                                       &root_path,
+                                                       Some("reserve"),
+                                                       &output_path),
+                   Err(format!("Body 'reserve' not found in '{}' (did you mean 'reserved'?)",
+                               root_path.display())));
+    }
+
+    #[test]
+    fn body_names_must_be_valid() {
+        for &(bodies, error) in &[("<body>a</body><body>b</body>", "Only one body may be unnamed"),
+                                  ("<body name=\"2\">a</body>", "Invalid body name '2'"),
+                                  ("<body name=\"\">a</body>", "Invalid body name ''")] {
+            let fixture = Fixture::new(&[("main.xml", &format!("<bbxml>{}</bbxml>", bodies))]);
+            assert_eq!(fixture.build(&fixture.load(), "main"),
+                       Err(format!("'{}': {}", fixture.dir.join("main.xml").display(), error)));
+        }
+    }
+
+    /// The project of a data target, which includes the `sheet` template of `main.xml` for each
+    /// row of a data file.
+    fn data_project(data: &str, key: &str) -> String {
+        format!(r#"<project><targets><target name="main" src="main.xml" template="sheet" data="{}" {} /></targets></project>"#,
+                data,
+                key)
+    }
+
+    /// A BBXML file defining the `sheet` template used by data targets.
+    static SHEET: &'static str = r#"<bbxml>
+                                        <templates>
+                                            <template name="sheet">{name}: <for each="skill" in="skills">[{skill}]</for></template>
+                                        </templates>
+                                    </bbxml>"#;
+
+    #[test]
+    fn data_targets_output_each_row() {
+        let fixture = Fixture::new(&[("project.xml", &data_project("rows.csv", "")),
+                                     ("main.xml", SHEET),
+                                     ("rows.csv", "\u{FEFF}name,skills\r\nAlice,{x}\r\n\"Bob, Jr.\",\r\n")]);
+        let project = fixture.load();
+        let root_path = fixture.dir.join("main.xml");
+        let output_path = fixture.dir.join("target/main.txt");
+        Parser::new(&project).output_bbcode(&project.targets["main"], &root_path, None, &output_path)
+            .unwrap();
+        assert_eq!(fixture.read("target/main.1.txt"), "Alice: [\u{200B}{x}]");
+        assert_eq!(fixture.read("target/main.2.txt"), "Bob, Jr.: [\u{200B}]");
+        assert!(!output_path.exists());
+    }
+
+    #[test]
+    fn data_targets_name_rows_by_key() {
+        let fixture = Fixture::new(&[("project.xml", &data_project("rows.json", "key=\"name\"")),
+                                     ("main.xml", SHEET),
+                                     ("rows.json",
+                                      r#"[{"name": "Jean Valjean", "skills": ["strength", "stealth"]},
+                                          {"name": "Javert/2", "skills": []}]"#)]);
+        let project = fixture.load();
+        let root_path = fixture.dir.join("main.xml");
+        let output_path = fixture.dir.join("target/main.txt");
+        Parser::new(&project).output_bbcode(&project.targets["main"], &root_path, None, &output_path)
+            .unwrap();
+        assert_eq!(fixture.read("target/main.Jean-Valjean.txt"),
+                   "Jean Valjean: [\u{200B}strength][\u{200B}stealth]");
+        assert_eq!(fixture.read("target/main.Javert-2.txt"), "Javert/2: ");
+
+        // A single row is built by its name, once its key is made into a file name
+        let row_path = fixture.dir.join("target/row.txt");
+        Parser::new(&project)
+            .output_bbcode(&project.targets["main"], &root_path, Some("Javert-2"), &row_path)
+            .unwrap();
+        assert_eq!(fixture.read("target/row.txt"), "Javert/2: ");
+        assert_eq!(Parser::new(&project).output_bbcode(&project.targets["main"],
+                                                       &root_path,
+                                                       Some("Javert"),
+                                                       &row_path),
+                   Err(format!("Row 'Javert' not found in '{}'",
+                               fixture.dir.join("rows.json").display())));
+    }
+
+    #[test]
+    fn data_target_keys_must_be_unique_and_not_empty() {
+        for &(rows, error) in &[("name\nAlice\n\"Alice \"\n", "Rows 1 and 2 are both named 'Alice'"),
+                                ("name\nA B\nA-B\n", "Rows 1 and 2 are both named 'A-B'"),
+                                ("name\nAlice\n\" \"\n", "Row 2 has an empty 'name' column"),
+                                ("id\n1\n", "Row 1 has no 'name' column")] {
+            let fixture = Fixture::new(&[("project.xml", &data_project("rows.csv", "key=\"name\"")),
+                                         ("main.xml", SHEET),
+                                         ("rows.csv", rows)]);
+            assert_eq!(fixture.build(&fixture.load(), "main"),
+                       Err(format!("'{}': {}", fixture.dir.join("rows.csv").display(), error)));
+        }
+    }
+
+    #[test]
+    fn data_fields_are_referenced_as_parameters() {
+        let fixture = Fixture::new(&[("main.xml",
+                                      r#"<bbxml>
+                                             <data src="stats.json" as="stats" />
+                                             <body>{stats.str}/{stats.skills.magic}/{stats.note}/{stats.missing}/{stats.str.x}</body>
+                                         </bbxml>"#),
+                                     ("stats.json",
+                                      r#"{"str": 12, "skills": {"magic": "high"}, "note": "{stats.str}"}"#)]);
+        assert_eq!(fixture.build(&fixture.load(), "main").unwrap(),
+                   "12/high/{stats.str}/{stats.missing}/{stats.str.x}");
+    }
+
+    #[test]
+    fn data_lists_are_iterated_and_passed() {
+        let fixture = Fixture::new(&[("main.xml",
+                                      r#"<bbxml>
+                                             <data src="roster.csv" as="roster" />
+                                             <templates>
+                                                 <template name="card">{member.name} ({member.role})</template>
+                                                 <template name="names"><for each="name" in="names">{name};</for></template>
+                                             </templates>
+                                             <body>
+                                                 <for each="member" in="roster"><include template="card"><param name="member">{member}</param></include>,</for>
+                                                 <include template="names"><param name="names">{roster.name}</param></include>
+                                             </body>
+                                         </bbxml>"#),
+                                     ("roster.csv", "name,role\nAlice,healer\nBob,tank\n")]);
+        assert_eq!(fixture.build(&fixture.load(), "main").unwrap(),
+                   "Alice (healer),Bob (tank),{roster.name};");
+    }
+
+    #[test]
+    fn data_is_passed_with_lexical_scoping() {
+        let fixture = Fixture::new(&[("project.xml",
+                                      r#"<project scoping="lexical"><targets><target name="main" src="main.xml" /></targets></project>"#),
+                                     ("main.xml",
+                                      r#"<bbxml>
+                                             <data src="stats.json" as="stats" />
+                                             <templates>
+                                                 <template name="card">{hero.name}: {stats.hp}</template>
+                                             </templates>
+                                             <body><include template="card"><param name="hero">{stats}</param></include></body>
+                                         </bbxml>"#),
+                                     ("stats.json", r#"{"name": "Alice", "hp": 30}"#)]);
+        assert_eq!(fixture.build(&fixture.load(), "main").unwrap(), "Alice: 30");
+    }
+
+    #[test]
+    fn data_elements_must_be_valid() {
+        for &(data, error) in &[("<data as=\"stats\" />", "Missing 'src' attribute in data"),
+                                ("<data src=\"stats.json\" />", "Missing 'as' attribute in data"),
+                                ("<data src=\"missing.json\" as=\"stats\" />",
+                                 "Data file 'missing.json' not found")] {
+            let fixture = Fixture::new(&[("main.xml", &format!("<bbxml>{}<body /></bbxml>", data))]);
+            assert_eq!(fixture.build(&fixture.load(), "main"),
+                       Err(format!("'{}': {}", fixture.dir.join("main.xml").display(), error)));
+        }
+    }
+}
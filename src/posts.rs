@@ -0,0 +1,236 @@
+extern crate regex;
+
+/// The tags whose content is not parsed as BBCode by forums.
+static NOPARSE_TAGS: &'static [&'static str] = &["noparse", "plain"];
+
+/// A tag which was opened and is not closed yet.
+#[derive(Clone)]
+struct OpenTag {
+    name: String,
+    opening: String,
+}
+
+/// A piece of BBCode which cannot be split: a whole tag, a `\r\n` line break or a single character.
+struct Token<'t> {
+    text: &'t str,
+    length: usize,
+    tag: Option<(bool, &'t str)>,
+}
+
+/// Counts the characters of a post, as forums do.
+pub fn length(text: &str) -> usize {
+    text.chars().count()
+}
+
+/// Splits BBCode into posts which are at most `max_length` characters long.
+///
+/// Posts are split after a line break if possible, and otherwise after a space. Tags which are
+/// still open at a split are closed at the end of the post and reopened at the start of the next,
+/// and count toward the length of both. A tag too long to fit in a post on its own is kept with the
+/// text which follows it up to the next split, in a post over the limit.
+pub fn split(text: &str, max_length: usize) -> Vec<String> {
+    let tokens = tokenize(text);
+    let mut posts = Vec::new();
+    let mut start = 0;
+    let mut open: Vec<OpenTag> = Vec::new();
+    loop {
+        let prefix: String = open.iter().map(|tag| tag.opening.as_str()).collect();
+        let mut post_length = length(&prefix);
+        let mut stack = open.clone();
+        let mut line_break: Option<(usize, Vec<OpenTag>)> = None;
+        let mut space_break: Option<(usize, Vec<OpenTag>)> = None;
+        let mut i = start;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            let mut next_stack = stack.clone();
+            apply_tag(&mut next_stack, token);
+            if post_length + token.length + closing_length(&next_stack) > max_length {
+                break;
+            }
+            post_length += token.length;
+            stack = next_stack;
+            i += 1;
+            if token.text == "\n" || token.text == "\r\n" {
+                line_break = Some((i, stack.clone()));
+            } else if token.tag.is_none() && token.text.trim().is_empty() {
+                space_break = Some((i, stack.clone()));
+            }
+        }
+
+        // A post always contains at least one token, even if it is too long on its own
+        if i == start {
+            while i < tokens.len() {
+                apply_tag(&mut stack, &tokens[i]);
+                i += 1;
+                if tokens[i - 1].tag.is_none() && tokens[i - 1].text.trim().is_empty() {
+                    break;
+                }
+            }
+        }
+
+        // The last post is left as it ends in the original text
+        if i == tokens.len() {
+            let rest: String = tokens[start..].iter().map(|token| token.text).collect();
+            posts.push(format!("{}{}", prefix, rest));
+            return posts;
+        }
+
+        // Line breaks are preferred, unless they would leave the post less than half full
+        let line_break = line_break.filter(|&(end, _)| (end - start) * 2 >= i - start);
+        let (end, end_stack) = match line_break.or(space_break) {
+            Some(split) => split,
+            None => (i, stack),
+        };
+        let content: String = tokens[start..end].iter().map(|token| token.text).collect();
+        let closing: String = end_stack.iter().rev().map(closing_tag).collect();
+        posts.push(format!("{}{}{}", prefix, content, closing));
+        start = end;
+        open = end_stack;
+    }
+}
+
+/// Cuts BBCode into tags, line breaks and single characters.
+fn tokenize<'t>(text: &'t str) -> Vec<Token<'t>> {
+    let tag_re = regex::Regex::new(r"\[(/?)([A-Za-z*][A-Za-z0-9]*)(?:=[^\]\n]*)?\]").unwrap();
+    let mut tokens = Vec::new();
+    let mut last_end = 0;
+    for tag in tag_re.captures_iter(text) {
+        let whole = tag.get(0).unwrap();
+        tokenize_text(text, last_end, whole.start(), &mut tokens);
+        tokens.push(Token {
+                        text: whole.as_str(),
+                        length: length(whole.as_str()),
+                        tag: Some((tag.get(1).unwrap().as_str() == "/",
+                                   tag.get(2).unwrap().as_str())),
+                    });
+        last_end = whole.end();
+    }
+    tokenize_text(text, last_end, text.len(), &mut tokens);
+    tokens
+}
+
+/// Cuts the text between two tags into characters, keeping `\r\n` line breaks whole so that a
+/// post is never split between their two characters.
+fn tokenize_text<'t>(text: &'t str, start: usize, end: usize, tokens: &mut Vec<Token<'t>>) {
+    let mut chars = text[start..end].char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let position = start + i;
+        let mut token_end = position + c.len_utf8();
+        let mut token_length = 1;
+        if c == '\r' && text[token_end..end].starts_with('\n') {
+            chars.next();
+            token_end += 1;
+            token_length = 2;
+        }
+        tokens.push(Token {
+                        text: &text[position..token_end],
+                        length: token_length,
+                        tag: None,
+                    });
+    }
+}
+
+/// Updates the list of open tags with a token.
+///
+/// List items have no closing tag, and the content of tags such as `[noparse]` is not parsed.
+fn apply_tag(stack: &mut Vec<OpenTag>, token: &Token) {
+    let (closing, name) = match token.tag {
+        Some(tag) => tag,
+        None => return,
+    };
+    let name = name.to_lowercase();
+    let in_noparse = match stack.last() {
+        Some(tag) => NOPARSE_TAGS.contains(&tag.name.as_str()),
+        None => false,
+    };
+    if closing {
+        if in_noparse && stack.last().unwrap().name != name {
+            return;
+        }
+        match stack.iter().rposition(|tag| tag.name == name) {
+            Some(position) => stack.truncate(position),
+            None => (),  // Stray closing tags are left as they are
+        }
+    } else if !in_noparse && name != "*" {
+        stack.push(OpenTag {
+                       name: name,
+                       opening: token.text.to_owned(),
+                   });
+    }
+}
+
+/// Creates the tag which closes an open tag, in the same case as it was opened.
+fn closing_tag(tag: &OpenTag) -> String {
+    let name_length = tag.name.len();
+    format!("[/{}]", &tag.opening[1..1 + name_length])
+}
+
+/// Computes the length of the tags which close all the open tags.
+fn closing_length(stack: &[OpenTag]) -> usize {
+    stack.iter().map(|tag| tag.name.chars().count() + 3).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_keeps_short_text_whole() {
+        assert_eq!(split("[b]Short[/b]\n", 20), vec!["[b]Short[/b]\n"]);
+    }
+
+    #[test]
+    fn split_respects_limit() {
+        let text = "[quote][b]Lorem ipsum[/b] dolor sit amet, [i]consectetur adipiscing elit, sed \
+                    do eiusmod tempor[/i] incididunt ut labore et dolore magna aliqua.[/quote]";
+        let posts = split(text, 40);
+        assert!(posts.len() > 1);
+        for post in &posts {
+            assert!(length(post) <= 40, "'{}' is too long", post);
+        }
+    }
+
+    #[test]
+    fn split_prefers_line_breaks_then_spaces() {
+        assert_eq!(split("ab cd\nef gh ij", 10), vec!["ab cd\n", "ef gh ij"]);
+        // A line break which would leave the post less than half full is not used
+        assert_eq!(split("a\nbcdef ghij", 10), vec!["a\nbcdef ", "ghij"]);
+        assert_eq!(split("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+    }
+
+    #[test]
+    fn split_keeps_crlf_line_breaks_whole() {
+        assert_eq!(split("ab cd\r\nef gh ij", 10), vec!["ab cd\r\n", "ef gh ij"]);
+        assert_eq!(split("abcd\r\nefgh", 5), vec!["abcd", "\r\nefg", "h"]);
+        assert_eq!(length("\r\n"), 2);
+    }
+
+    #[test]
+    fn split_reopens_tags() {
+        assert_eq!(split("[b]aaaa bbbb[/b]", 12), vec!["[b]aaaa [/b]", "[b]bbbb[/b]"]);
+        assert_eq!(split("[COLOR=red][i]aaaa bbbb[/i][/COLOR]", 31),
+                   vec!["[COLOR=red][i]aaaa [/i][/COLOR]", "[COLOR=red][i]bbbb[/i][/COLOR]"]);
+    }
+
+    #[test]
+    fn split_ignores_list_items() {
+        assert_eq!(split("[list][*]aaaa [*]bbbb[/list]", 21),
+                   vec!["[list][*]aaaa [/list]", "[list][*]bbbb[/list]"]);
+    }
+
+    #[test]
+    fn split_ignores_tags_within_noparse() {
+        assert_eq!(split("[noparse][b]aaa [i]bbb[/noparse]", 27),
+                   vec!["[noparse][b]aaa [/noparse]", "[noparse][i]bbb[/noparse]"]);
+    }
+
+    #[test]
+    fn split_keeps_long_tokens_whole() {
+        assert_eq!(split("[url=http://example.com]x[/url]", 10),
+                   vec!["[url=http://example.com]x[/url]"]);
+        assert_eq!(split("[url=http://example.com]x y[/url] and more", 12),
+                   vec!["[url=http://example.com]x [/url]",
+                        "[url=http://example.com]y[/url] ",
+                        "and more"]);
+    }
+}
@@ -1,5 +1,8 @@
 extern crate elementtree;
+extern crate regex;
 
+use dialect;
+use whitespace;
 use std;
 
 /// The default target to run if no target was specified and no default target was user-specified.
@@ -9,8 +12,29 @@ pub static ALL_TARGETS: &'static str = "_all";
 pub struct Project {
     pub project_directory: std::path::PathBuf,
     pub include: Vec<std::path::PathBuf>,
-    pub targets: std::collections::HashMap<String, String>,
+    pub targets: std::collections::HashMap<String, Target>,
+    pub groups: std::collections::HashMap<String, Vec<String>>,
     pub default_target: String,
+    pub vars: std::collections::HashMap<String, elementtree::Element>,
+    pub defines: std::collections::HashMap<String, elementtree::Element>,
+    pub strict: bool,
+    pub lexical_scoping: bool,
+}
+
+/// Contains data about a single build target.
+pub struct Target {
+    pub src: String,
+    pub body: Option<String>,
+    pub template: Option<String>,
+    pub data: Option<String>,
+    pub key: Option<String>,
+    pub vars: std::collections::HashMap<String, elementtree::Element>,
+    pub dialect: dialect::Dialect,
+    pub minify: bool,
+    pub whitespace: whitespace::Whitespace,
+    pub line_endings: whitespace::LineEndings,
+    pub trailing_newline: whitespace::TrailingNewline,
+    pub split: bool,
 }
 
 impl Project {
@@ -20,7 +44,12 @@ impl Project {
             project_directory: std::path::PathBuf::new(),
             include: Vec::new(),
             targets: std::collections::HashMap::new(),
+            groups: std::collections::HashMap::new(),
             default_target: "main".to_owned(),
+            vars: std::collections::HashMap::new(),
+            defines: std::collections::HashMap::new(),
+            strict: false,
+            lexical_scoping: false,
         }
     }
 
@@ -40,6 +69,13 @@ impl Project {
         };
         self.project_directory = project_file_path.parent().unwrap().to_owned();
 
+        // Load the scoping mode of template parameters
+        self.lexical_scoping = match root.get_attr("scoping") {
+            Some("lexical") => true,
+            Some("dynamic") | None => false,
+            Some(scoping) => return Err(format!("Unknown scoping mode '{}'", scoping)),
+        };
+
         // Load the include paths
         // These specify additional paths to search source files in
         match root.find("include") {
@@ -52,20 +88,137 @@ impl Project {
             None => (),  // Do not add any additional include paths
         };
 
+        // Load the project-wide variables
+        match root.find("vars") {
+            Some(element) => self.vars = parse_vars(element)?,
+            None => (),  // No project-wide variables
+        };
+
         // Load the targets
-        // A target consists of a name and a root source file
+        // A target consists of a name, a root source file, its own variables and the dialect it
+        // is written in; the dialect, its escaping strategy and the output options can be set for
+        // all targets at once, and a target may extend another to override some of them
         let targets: &elementtree::Element = match root.find("targets") {
             Some(element) => element,
             None => return Err("No target definitions found".to_owned()),
         };
-        self.targets = targets
-            .find_all("target")
-            .map(|target: &elementtree::Element| {
-                     // TODO(Lyrositor) Handle missing name or src
-                     (target.get_attr("name").unwrap().to_owned(),
-                      target.get_attr("src").unwrap().to_owned())
-                 })
-            .collect();
+        let default_dialect = targets
+            .get_attr("dialect")
+            .unwrap_or(dialect::DEFAULT_DIALECT);
+        let default_escape = targets.get_attr("escape");
+        let default_max_length = targets.get_attr("max-length");
+        let default_minify = targets.get_attr("minify").unwrap_or("false");
+        let default_split = targets.get_attr("split").unwrap_or("false");
+        let default_whitespace = targets
+            .get_attr("whitespace")
+            .unwrap_or(whitespace::DEFAULT_WHITESPACE);
+        let default_line_endings = targets
+            .get_attr("line-endings")
+            .unwrap_or(whitespace::DEFAULT_LINE_ENDINGS);
+        let default_trailing_newline = targets
+            .get_attr("trailing-newline")
+            .unwrap_or(whitespace::DEFAULT_TRAILING_NEWLINE);
+        let mut definitions = std::collections::HashMap::new();
+        for target in targets.find_all("target") {
+            match target.get_attr("name") {
+                Some(name) => definitions.insert(name, target),
+                None => return Err("Missing 'name' attribute in target".to_owned()),
+            };
+        }
+        for &name in definitions.keys() {
+            let target = &resolve_target(name, &definitions, &mut Vec::new())?;
+
+            // A target's source may be a single body of a file, given as `file.xml#body`
+            let (src, body) = match target.get_attr("src") {
+                Some(src) => {
+                    match src.find('#') {
+                        Some(index) => (&src[..index], Some(src[index + 1..].to_owned())),
+                        None => (src, None),
+                    }
+                }
+                None => return Err(format!("Missing 'src' attribute in target '{}'", name)),
+            };
+            let data = target.get_attr("data").map(|data| data.to_owned());
+            if data.is_some() && target.get_attr("template").is_none() {
+                return Err(format!("Missing 'template' attribute in data target '{}'", name));
+            }
+            let whitespace_name = target
+                .get_attr("whitespace")
+                .unwrap_or(default_whitespace);
+            let target_whitespace = match whitespace::Whitespace::from_name(whitespace_name) {
+                Some(target_whitespace) => target_whitespace,
+                None => return Err(format!("Unknown whitespace mode '{}'", whitespace_name)),
+            };
+            let line_endings_name = target
+                .get_attr("line-endings")
+                .unwrap_or(default_line_endings);
+            let line_endings = match whitespace::LineEndings::from_name(line_endings_name) {
+                Some(line_endings) => line_endings,
+                None => return Err(format!("Unknown line endings '{}'", line_endings_name)),
+            };
+            let trailing_newline_name = target
+                .get_attr("trailing-newline")
+                .unwrap_or(default_trailing_newline);
+            let trailing_newline =
+                match whitespace::TrailingNewline::from_name(trailing_newline_name) {
+                    Some(trailing_newline) => trailing_newline,
+                    None => {
+                        return Err(format!("Unknown trailing newline behavior '{}'",
+                                           trailing_newline_name))
+                    }
+                };
+            self.targets.insert(name.to_owned(),
+                                Target {
+                                    src: src.to_owned(),
+                                    body: body,
+                                    template: target
+                                        .get_attr("template")
+                                        .map(|template| template.to_owned()),
+                                    data: data,
+                                    key: target.get_attr("key").map(|key| key.to_owned()),
+                                    vars: parse_vars(target)?,
+                                    dialect: parse_dialect(target,
+                                                           default_dialect,
+                                                           default_escape,
+                                                           default_max_length)?,
+                                    minify: match target
+                                              .get_attr("minify")
+                                              .unwrap_or(default_minify) {
+                                        "true" | "1" => true,
+                                        _ => false,
+                                    },
+                                    whitespace: target_whitespace,
+                                    line_endings: line_endings,
+                                    trailing_newline: trailing_newline,
+                                    split: match target
+                                             .get_attr("split")
+                                             .unwrap_or(default_split) {
+                                        "true" | "1" => true,
+                                        _ => false,
+                                    },
+                                });
+        }
+
+        // Load the target groups, whose members are target names, glob patterns or other groups
+        for group in targets.find_all("group") {
+            let name = match group.get_attr("name") {
+                Some(name) => name,
+                None => return Err("Missing 'name' attribute in group".to_owned()),
+            };
+            if self.targets.contains_key(name) {
+                return Err(format!("Group '{}' has the same name as a target", name));
+            }
+            let members = match group.get_attr("targets") {
+                Some(members) => {
+                    members
+                        .split_whitespace()
+                        .map(|member| member.to_owned())
+                        .collect()
+                }
+                None => return Err(format!("Missing 'targets' attribute in group '{}'", name)),
+            };
+            self.groups.insert(name.to_owned(), members);
+        }
 
         // Load the default target, replacing it by the default "default target" if not found
         self.default_target = targets
@@ -76,6 +229,70 @@ impl Project {
         Ok(())
     }
 
+    /// Finds the targets designated by a name, in the order they are designated, along with the
+    /// body to build for each of them if only one is.
+    ///
+    /// The name may be that of a target or a group, a glob pattern such as `char-*` matching target
+    /// names, or `_all`, followed by `#body` to build a single body of each target. A name which
+    /// designates nothing is an error.
+    pub fn find_targets(&self, name: &str) -> Result<Vec<(String, Option<String>)>, String> {
+        let mut builds = Vec::new();
+        self.find_targets_in(name, None, &mut builds, &mut Vec::new())?;
+        Ok(builds)
+    }
+
+    /// Adds the targets designated by a name to a list, skipping those already in it.
+    ///
+    /// A body given for a group applies to all its members, taking precedence over those of the
+    /// members themselves. `chain` holds the groups being expanded, to detect circular groups.
+    fn find_targets_in(&self,
+                       name: &str,
+                       body: Option<&str>,
+                       builds: &mut Vec<(String, Option<String>)>,
+                       chain: &mut Vec<String>)
+                       -> Result<(), String> {
+        let (name, body) = match name.find('#') {
+            Some(index) => (&name[..index], body.or(Some(&name[index + 1..]))),
+            None => (name, body),
+        };
+        let mut found: Vec<String> = if name == ALL_TARGETS {
+            self.targets.keys().cloned().collect()
+        } else if self.targets.contains_key(name) {
+            vec![name.to_owned()]
+        } else if let Some(members) = self.groups.get(name) {
+            if chain.iter().any(|expanding| expanding == name) {
+                chain.push(name.to_owned());
+                return Err(format!("Circular group: {}", chain.join(" -> ")));
+            }
+            chain.push(name.to_owned());
+            for member in members {
+                self.find_targets_in(member, body, builds, chain)?;
+            }
+            chain.pop();
+            return Ok(());
+        } else if name.contains(&['*', '?'][..]) {
+            let pattern = glob_regex(name);
+            self.targets
+                .keys()
+                .filter(|target| pattern.is_match(target))
+                .cloned()
+                .collect()
+        } else {
+            return Err(format!("Target '{}' not found", name));
+        };
+        if found.is_empty() {
+            return Err(format!("No target matches '{}'", name));
+        }
+        found.sort();
+        for target in found {
+            let build = (target, body.map(|body| body.to_owned()));
+            if !builds.contains(&build) {
+                builds.push(build);
+            }
+        }
+        Ok(())
+    }
+
     /// Finds a file within the project, using its include paths to search for it.
     ///
     /// The order of lookup for relative paths:
@@ -83,6 +300,7 @@ impl Project {
     /// - including file's directory
     /// - every project include path, in the order in which they were defined, relative to the
     ///   project directory
+    ///
     /// If the path is absolute, then only an absolute lookup is performed.
     pub fn find_file(&self,
                      filename: &String,
@@ -100,11 +318,260 @@ impl Project {
                 .collect();
         }
 
-        for path in possible_paths {
-            if path.exists() {
-                return Some(path);
+        possible_paths.into_iter().find(|path| path.exists())
+    }
+}
+
+/// Parses the `<var>` children of an element into a map of variable names to their values.
+///
+/// A variable's value is either its `value` attribute or its content, which may contain BBXML.
+pub fn parse_vars(element: &elementtree::Element)
+                  -> Result<std::collections::HashMap<String, elementtree::Element>, String> {
+    let mut vars = std::collections::HashMap::new();
+    for var in element.find_all("var") {
+        let name = match var.get_attr("name") {
+            Some(name) => name,
+            None => return Err("Missing 'name' attribute in var".to_owned()),
+        };
+        let value = match var.get_attr("value") {
+            Some(value) => text_var(value),
+            None => var.clone(),
+        };
+        vars.insert(name.to_owned(), value);
+    }
+    Ok(vars)
+}
+
+/// Converts a glob pattern, where `*` matches any text and `?` any single character, to a regular
+/// expression matching whole names.
+fn glob_regex(pattern: &str) -> regex::Regex {
+    let mut expression = "^".to_owned();
+    for c in pattern.chars() {
+        match c {
+            '*' => expression.push_str(".*"),
+            '?' => expression.push('.'),
+            _ => expression.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    expression.push('$');
+    regex::Regex::new(&expression).unwrap()
+}
+
+/// Resolves a target which extends another, creating a target definition which includes the
+/// attributes and variables of its parents.
+///
+/// `chain` holds the targets being resolved, to detect circular inheritance.
+fn resolve_target(name: &str,
+                  definitions: &std::collections::HashMap<&str, &elementtree::Element>,
+                  chain: &mut Vec<String>)
+                  -> Result<elementtree::Element, String> {
+    if chain.iter().any(|resolving| resolving == name) {
+        chain.push(name.to_owned());
+        return Err(format!("Circular target inheritance: {}", chain.join(" -> ")));
+    }
+    let target = definitions[name];
+    let base_name = match target.get_attr("extends") {
+        Some(base_name) => base_name,
+        None => return Ok(target.clone()),
+    };
+    if !definitions.contains_key(base_name) {
+        return Err(format!("Target '{}' extends unknown target '{}'", name, base_name));
+    }
+    chain.push(name.to_owned());
+    let mut resolved = resolve_target(base_name, definitions, chain)?;
+    chain.pop();
+
+    // The target's attributes override its base's, and its variables are added after them
+    for (attr_name, value) in target.attrs() {
+        resolved.set_attr(attr_name.name(), value);
+    }
+    resolved.remove_attr("extends");
+    for var in target.find_all("var") {
+        resolved.append_child(var.clone());
+    }
+    Ok(resolved)
+}
+
+/// Parses the `dialect`, `escape` and `max-length` attributes of an element, falling back to the
+/// defaults given.
+///
+/// If no escaping strategy or maximum length is specified, the dialect's own is used. A maximum
+/// length of `none` removes the dialect's limit.
+fn parse_dialect(element: &elementtree::Element,
+                 default_dialect: &str,
+                 default_escape: Option<&str>,
+                 default_max_length: Option<&str>)
+                 -> Result<dialect::Dialect, String> {
+    let name = element.get_attr("dialect").unwrap_or(default_dialect);
+    let mut target_dialect = match dialect::Dialect::from_name(name) {
+        Some(target_dialect) => target_dialect,
+        None => return Err(format!("Unknown dialect '{}'", name)),
+    };
+    match element.get_attr("escape").or(default_escape) {
+        Some(escape) => {
+            target_dialect.escape = match dialect::Escape::from_name(escape) {
+                Some(escape) => escape,
+                None => return Err(format!("Unknown escaping strategy '{}'", escape)),
             }
         }
-        None
+        None => (),  // Use the dialect's escaping strategy
+    }
+    match element.get_attr("max-length").or(default_max_length) {
+        Some("none") => target_dialect.max_length = None,
+        Some(max_length) => {
+            target_dialect.max_length = match max_length.parse::<usize>() {
+                Ok(max_length) if max_length > 0 => Some(max_length),
+                _ => return Err(format!("Invalid maximum length '{}'", max_length)),
+            }
+        }
+        None => (),  // Use the dialect's maximum length
+    }
+    Ok(target_dialect)
+}
+
+/// Parses a `name=value` variable definition, such as those given on the command line.
+pub fn parse_define(define: &str) -> Result<(String, elementtree::Element), String> {
+    let mut parts = define.splitn(2, '=');
+    let name = parts.next().unwrap().trim();
+    match parts.next() {
+        Some(value) if !name.is_empty() => Ok((name.to_owned(), text_var(value))),
+        _ => Err(format!("Invalid variable definition '{}', expected 'name=value'", define)),
+    }
+}
+
+/// Creates a variable whose value is the specified text.
+fn text_var(value: &str) -> elementtree::Element {
+    let mut element = elementtree::Element::new("var");
+    element.set_text(value);
+    element
+}
+
+#[cfg(test)]
+mod tests {
+    use std;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    /// The number of projects loaded so far, used to give each of them its own directory.
+    static PROJECTS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Loads a project whose `<targets>` element has the specified content.
+    fn load(targets: &str) -> Result<Project, String> {
+        let dir = std::env::temp_dir().join(format!("bbcoder-project-test-{}-{}",
+                                                    std::process::id(),
+                                                    PROJECTS.fetch_add(1, Ordering::SeqCst)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("project.xml");
+        std::fs::write(&path, format!("<project><targets>{}</targets></project>", targets))
+            .unwrap();
+        let mut project = Project::new();
+        let result = project.load(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.map(|_| project)
+    }
+
+    /// Finds the text of a target's variable.
+    fn var(project: &Project, target: &str, name: &str) -> String {
+        project.targets[target].vars[name].text().to_owned()
+    }
+
+    #[test]
+    fn targets_inherit_from_their_base() {
+        let project = load(r#"<target name="base" src="main.xml#opening" dialect="phpbb" minify="true">
+                                  <var name="palette" value="light" />
+                                  <var name="font" value="serif" />
+                              </target>
+                              <target name="dark" extends="base" minify="false">
+                                  <var name="palette" value="dark" />
+                              </target>
+                              <target name="darker" extends="dark" split="true" />"#)
+            .unwrap();
+        let darker = &project.targets["darker"];
+        assert_eq!((darker.src.as_str(), darker.body.as_deref()), ("main.xml", Some("opening")));
+        assert_eq!(darker.dialect.escape, dialect::Escape::ZeroWidthSpace);
+        assert_eq!((darker.minify, darker.split), (false, true));
+        assert_eq!(var(&project, "darker", "palette"), "dark");
+        assert_eq!(var(&project, "darker", "font"), "serif");
+        assert_eq!(var(&project, "base", "palette"), "light");
+        assert!(project.targets["base"].minify);
+    }
+
+    #[test]
+    fn target_inheritance_errors() {
+        assert_eq!(load(r#"<target name="a" extends="b" />"#).err(),
+                   Some("Target 'a' extends unknown target 'b'".to_owned()));
+        assert_eq!(load(r#"<target name="a" src="main.xml" extends="a" />"#).err(),
+                   Some("Circular target inheritance: a -> a".to_owned()));
+        let error = load(r#"<target name="a" src="main.xml" extends="b" />
+                            <target name="b" extends="a" />"#)
+            .err()
+            .unwrap();
+        assert!(error == "Circular target inheritance: a -> b -> a" ||
+                error == "Circular target inheritance: b -> a -> b",
+                "{}",
+                error);
+    }
+
+    /// Lists targets along with the bodies to build, as found by `find_targets`.
+    fn builds(list: &[(&str, Option<&str>)]) -> Vec<(String, Option<String>)> {
+        list.iter()
+            .map(|&(target, body)| (target.to_owned(), body.map(|body| body.to_owned())))
+            .collect()
+    }
+
+    #[test]
+    fn glob_patterns_match_whole_names() {
+        let pattern = glob_regex("char-*");
+        assert!(pattern.is_match("char-"));
+        assert!(pattern.is_match("char-alice"));
+        assert!(!pattern.is_match("my-char-alice"));
+        let pattern = glob_regex("post-?.x");
+        assert!(pattern.is_match("post-1.x"));
+        assert!(!pattern.is_match("post-12.x"));
+        assert!(!pattern.is_match("post-1-x"));
+    }
+
+    #[test]
+    fn groups_expand_to_their_targets() {
+        let project = load(r#"<target name="opening" src="thread.xml" />
+                              <target name="char-bob" src="bob.xml" />
+                              <target name="char-alice" src="alice.xml" />
+                              <target name="rules" src="rules.xml" />
+                              <group name="characters" targets="char-*" />
+                              <group name="thread" targets="opening characters char-bob rules#short" />"#)
+            .unwrap();
+        assert_eq!(project.find_targets("thread").unwrap(),
+                   builds(&[("opening", None),
+                            ("char-alice", None),
+                            ("char-bob", None),
+                            ("rules", Some("short"))]));
+        assert_eq!(project.find_targets("thread#reserved").unwrap(),
+                   builds(&[("opening", Some("reserved")),
+                            ("char-alice", Some("reserved")),
+                            ("char-bob", Some("reserved")),
+                            ("rules", Some("reserved"))]));
+        assert_eq!(project.find_targets("_all").unwrap(),
+                   builds(&[("char-alice", None),
+                            ("char-bob", None),
+                            ("opening", None),
+                            ("rules", None)]));
+        assert_eq!(project.find_targets("opening#reserved").unwrap(),
+                   builds(&[("opening", Some("reserved"))]));
+    }
+
+    #[test]
+    fn target_group_errors() {
+        let project = load(r#"<target name="a" src="a.xml" />
+                              <group name="loop" targets="a cycle" />
+                              <group name="cycle" targets="loop" />"#)
+            .unwrap();
+        assert_eq!(project.find_targets("b"), Err("Target 'b' not found".to_owned()));
+        assert_eq!(project.find_targets("b*"), Err("No target matches 'b*'".to_owned()));
+        assert_eq!(project.find_targets("loop"),
+                   Err("Circular group: loop -> cycle -> loop".to_owned()));
+        assert_eq!(load(r#"<target name="a" src="a.xml" /><group name="a" targets="a" />"#).err(),
+                   Some("Group 'a' has the same name as a target".to_owned()));
+        assert_eq!(load(r#"<group name="g" />"#).err(),
+                   Some("Missing 'targets' attribute in group 'g'".to_owned()));
     }
 }
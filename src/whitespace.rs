@@ -0,0 +1,149 @@
+/// The default whitespace mode, used when a target does not specify one.
+pub static DEFAULT_WHITESPACE: &'static str = "compact";
+
+/// The tags which start a new block, and around which collapsed whitespace is dropped.
+pub static BLOCK_TAGS: &'static [&'static str] =
+    &["accordion", "blockquote", "center", "code", "div", "divide", "fieldset", "float", "h1",
+      "h2", "h3", "h4", "h5", "h6", "hr", "indent", "justify", "left", "list", "quote", "right",
+      "side", "slide", "spoiler", "table", "td", "th", "tr"];
+
+/// A way of handling the whitespace in the text of BBXML files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Whitespace {
+    /// Line breaks are removed, along with the indentation around them.
+    Compact,
+    /// Runs of whitespace are collapsed into a single space, which is dropped at block boundaries.
+    Collapse,
+    /// Text is written as it is.
+    Preserve,
+    /// Line breaks are kept, but the indentation and trailing whitespace of each line are removed.
+    PreserveLines,
+    /// Whitespace is removed at both ends of each text, and kept as it is within it.
+    Trim,
+}
+
+impl Whitespace {
+    /// Finds a whitespace mode by name.
+    pub fn from_name(name: &str) -> Option<Whitespace> {
+        match name {
+            "compact" => Some(Whitespace::Compact),
+            "collapse" => Some(Whitespace::Collapse),
+            "preserve" => Some(Whitespace::Preserve),
+            "preserve-lines" => Some(Whitespace::PreserveLines),
+            "trim" => Some(Whitespace::Trim),
+            _ => None,
+        }
+    }
+}
+
+/// Checks whether a tag starts a new block.
+pub fn is_block_tag(name: &str) -> bool {
+    BLOCK_TAGS.binary_search(&name).is_ok()
+}
+
+/// The default line endings, used when a target does not specify them.
+pub static DEFAULT_LINE_ENDINGS: &'static str = "lf";
+
+/// The default trailing newline behavior, used when a target does not specify one.
+pub static DEFAULT_TRAILING_NEWLINE: &'static str = "keep";
+
+/// The characters which end each line of an output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineEndings {
+    /// Lines end with `\n`.
+    Lf,
+    /// Lines end with `\r\n`.
+    CrLf,
+}
+
+impl LineEndings {
+    /// Finds line endings by name.
+    pub fn from_name(name: &str) -> Option<LineEndings> {
+        match name {
+            "lf" => Some(LineEndings::Lf),
+            "crlf" => Some(LineEndings::CrLf),
+            _ => None,
+        }
+    }
+}
+
+/// The way the end of an output is handled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrailingNewline {
+    /// The output ends as it was generated.
+    Keep,
+    /// The output always ends with exactly one newline.
+    Always,
+    /// The output never ends with a newline.
+    Never,
+}
+
+impl TrailingNewline {
+    /// Finds a trailing newline behavior by name.
+    pub fn from_name(name: &str) -> Option<TrailingNewline> {
+        match name {
+            "keep" => Some(TrailingNewline::Keep),
+            "always" => Some(TrailingNewline::Always),
+            "never" => Some(TrailingNewline::Never),
+            _ => None,
+        }
+    }
+}
+
+/// Normalizes the newlines of an output, whatever mix of `\r\n`, `\r` and `\n` it contains.
+pub fn format_lines(text: &str,
+                    line_endings: LineEndings,
+                    trailing_newline: TrailingNewline)
+                    -> String {
+    let mut formatted = text.replace("\r\n", "\n").replace('\r', "\n");
+    match trailing_newline {
+        TrailingNewline::Keep => (),
+        TrailingNewline::Always => {
+            formatted.truncate(formatted.trim_end_matches('\n').len());
+            formatted.push('\n');
+        }
+        TrailingNewline::Never => formatted.truncate(formatted.trim_end_matches('\n').len()),
+    }
+    match line_endings {
+        LineEndings::Lf => formatted,
+        LineEndings::CrLf => formatted.replace('\n', "\r\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_tags_are_sorted() {
+        let mut sorted = BLOCK_TAGS.to_vec();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted, BLOCK_TAGS);
+    }
+
+    #[test]
+    fn whitespace_modes_are_found_by_name() {
+        assert_eq!(Whitespace::from_name(DEFAULT_WHITESPACE), Some(Whitespace::Compact));
+        assert_eq!(Whitespace::from_name("collapse"), Some(Whitespace::Collapse));
+        assert_eq!(Whitespace::from_name("preserve"), Some(Whitespace::Preserve));
+        assert_eq!(Whitespace::from_name("preserve-lines"), Some(Whitespace::PreserveLines));
+        assert_eq!(Whitespace::from_name("trim"), Some(Whitespace::Trim));
+        assert_eq!(Whitespace::from_name("html"), None);
+    }
+
+    #[test]
+    fn line_endings_are_normalized() {
+        assert_eq!(format_lines("a\r\nb\rc\n", LineEndings::Lf, TrailingNewline::Keep), "a\nb\nc\n");
+        assert_eq!(format_lines("a\r\nb\rc\n", LineEndings::CrLf, TrailingNewline::Keep),
+                   "a\r\nb\r\nc\r\n");
+    }
+
+    #[test]
+    fn trailing_newlines_are_added_or_removed() {
+        assert_eq!(format_lines("a\n\n", LineEndings::Lf, TrailingNewline::Always), "a\n");
+        assert_eq!(format_lines("a", LineEndings::CrLf, TrailingNewline::Always), "a\r\n");
+        assert_eq!(format_lines("a\r\n\n", LineEndings::Lf, TrailingNewline::Never), "a");
+        assert_eq!(format_lines("a", LineEndings::Lf, TrailingNewline::Keep), "a");
+    }
+}